@@ -0,0 +1,22 @@
+// Any bytes given to the decoders must give an error, never a panic
+//
+// cargo +nightly fuzz run decode -- -max_total_time=60
+#![no_main]
+
+use std::io::{self, Cursor, Read};
+
+use cm_tool::{SeekableDecoder, decompress, write_info};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decompress(data);
+
+    // info also builds the codes of the first table
+    let _ = write_info(data, &mut io::sink(), "fuzz.huff", None);
+
+    let Ok(mut decoder) = SeekableDecoder::new(Cursor::new(data)) else {
+        return;
+    };
+    let mut decoded: Vec<u8> = Vec::new();
+    let _ = decoder.read_to_end(&mut decoded);
+});
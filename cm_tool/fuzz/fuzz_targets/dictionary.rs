@@ -0,0 +1,8 @@
+#![no_main]
+
+use cm_tool::Dictionary;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Dictionary::read(&mut &data[..]);
+});
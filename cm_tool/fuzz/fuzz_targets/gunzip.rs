@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::io;
+
+use cm_tool::gunzip_stream;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = gunzip_stream(data, io::sink());
+});
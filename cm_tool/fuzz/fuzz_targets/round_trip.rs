@@ -0,0 +1,37 @@
+// The first two bytes pick the options, the rest is compressed and must
+// come back unchanged
+#![no_main]
+
+use cm_tool::{Coder, EncodeOptions, compress_with, decompress};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let [mode, size, input @ ..] = data else {
+        return;
+    };
+    let base = EncodeOptions {
+        block_size: 1 + *size as usize * 64,
+        ..EncodeOptions::default()
+    };
+    let options = match mode % 5 {
+        0 => base,
+        1 => EncodeOptions {
+            adaptive: true,
+            ..base
+        },
+        2 => EncodeOptions {
+            context: true,
+            ..base
+        },
+        3 => EncodeOptions {
+            level: 1 + mode / 5 % 9,
+            ..base
+        },
+        _ => EncodeOptions {
+            coder: Coder::Ans,
+            ..base
+        },
+    };
+    let compressed = compress_with(input, &options).unwrap();
+    assert_eq!(decompress(&compressed).unwrap(), input);
+});
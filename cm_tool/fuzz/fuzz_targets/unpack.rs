@@ -0,0 +1,16 @@
+// Multi-file archives: the entry list and every entry's stream
+#![no_main]
+
+use std::io::{self, Cursor};
+
+use cm_tool::ArchiveReader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut reader) = ArchiveReader::new(Cursor::new(data)) else {
+        return;
+    };
+    for entry in reader.entries().to_vec() {
+        let _ = reader.extract(&entry, io::sink());
+    }
+});
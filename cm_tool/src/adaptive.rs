@@ -0,0 +1,234 @@
+// Adaptive Huffman coding (FGK algorithm)
+//
+// Encoder and decoder start from the same tree holding a single NYT
+// ("not yet transmitted") leaf and update it the same way after every
+// byte, so no code table has to be stored and a single pass is enough.
+// A byte seen for the first time is sent as the code of NYT followed by
+// its 8 raw bits, then NYT is split to give the byte its own leaf.
+//
+// Nodes are kept in an array ordered by their implicit number: weights never
+// decrease with the index (sibling property), the root is the last node and
+// the left child of a node comes before its right child.
+use std::io::{self, Read, Write};
+
+use crate::bit_io::{BitReader, BitWriter};
+
+// 256 byte leaves and NYT make 257 leaves, so 513 nodes at most
+const MAX_NODES: usize = 2 * 257 - 1;
+const ROOT: usize = MAX_NODES - 1;
+const NONE: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    weight: u64,
+    parent: usize,
+    // NONE for leaves
+    left: usize,
+    right: usize,
+    // only meaningful for byte leaves
+    byte: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptiveHuffman {
+    nodes: Vec<Node>,
+    // Leaf of every byte already seen
+    leaf_of: [usize; 256],
+    nyt: usize,
+}
+
+impl Default for AdaptiveHuffman {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdaptiveHuffman {
+    pub fn new() -> Self {
+        let empty = Node {
+            weight: 0,
+            parent: NONE,
+            left: NONE,
+            right: NONE,
+            byte: 0,
+        };
+        AdaptiveHuffman {
+            nodes: vec![empty; MAX_NODES],
+            leaf_of: [NONE; 256],
+            nyt: ROOT,
+        }
+    }
+
+    pub fn encode<W: Write>(&mut self, byte: u8, writer: &mut BitWriter<W>) -> io::Result<()> {
+        let leaf = self.leaf_of[byte as usize];
+        if leaf == NONE {
+            // Escape with the NYT code, then the byte itself
+            self.write_path(self.nyt, writer)?;
+            writer.write_bits(byte as u64, 8)?;
+        } else {
+            self.write_path(leaf, writer)?;
+        }
+        self.update(byte);
+        Ok(())
+    }
+
+    pub fn decode<R: Read>(&mut self, reader: &mut BitReader<R>) -> io::Result<u8> {
+        // Walk down from the root to a leaf
+        let mut node = ROOT;
+        while self.nodes[node].left != NONE {
+            node = if reader.read_bit()? {
+                self.nodes[node].right
+            } else {
+                self.nodes[node].left
+            };
+        }
+
+        let byte = if node == self.nyt {
+            // New byte, it follows as 8 raw bits
+            let byte = reader.peek_bits(8)? as u8;
+            reader.consume(8)?;
+            byte
+        } else {
+            self.nodes[node].byte
+        };
+        self.update(byte);
+        Ok(byte)
+    }
+
+    // Writes the code of a node: the branches taken from the root to reach it
+    fn write_path<W: Write>(&self, node: usize, writer: &mut BitWriter<W>) -> io::Result<()> {
+        let mut path: Vec<bool> = Vec::new();
+        let mut node = node;
+        while node != ROOT {
+            let parent = self.nodes[node].parent;
+            path.push(self.nodes[parent].right == node);
+            node = parent;
+        }
+        for bit in path.into_iter().rev() {
+            writer.write_bits(bit as u64, 1)?;
+        }
+        Ok(())
+    }
+
+    // Adds one occurrence of `byte` to the tree, keeping the sibling property
+    fn update(&mut self, byte: u8) {
+        let mut node = self.leaf_of[byte as usize];
+
+        if node == NONE {
+            // Split NYT into a new NYT (left) and a leaf for the byte (right)
+            let old_nyt = self.nyt;
+            let (new_nyt, leaf) = (old_nyt - 2, old_nyt - 1);
+            self.nodes[old_nyt].left = new_nyt;
+            self.nodes[old_nyt].right = leaf;
+            self.nodes[new_nyt] = Node {
+                weight: 0,
+                parent: old_nyt,
+                left: NONE,
+                right: NONE,
+                byte: 0,
+            };
+            self.nodes[leaf] = Node {
+                weight: 0,
+                parent: old_nyt,
+                left: NONE,
+                right: NONE,
+                byte,
+            };
+            self.leaf_of[byte as usize] = leaf;
+            self.nyt = new_nyt;
+            node = leaf;
+        }
+
+        while node != NONE {
+            // Move the node to the highest number of its weight class,
+            // unless that is its own parent
+            let leader = self.leader(node);
+            if leader != node && leader != self.nodes[node].parent {
+                self.swap(node, leader);
+                node = leader;
+            }
+            self.nodes[node].weight += 1;
+            node = self.nodes[node].parent;
+        }
+    }
+
+    // Highest numbered node with the same weight as `node`
+    fn leader(&self, node: usize) -> usize {
+        let weight = self.nodes[node].weight;
+        let mut leader = node;
+        while leader < ROOT && self.nodes[leader + 1].weight == weight {
+            leader += 1;
+        }
+        leader
+    }
+
+    // Swaps the subtrees at two positions of the tree, parents stay in place.
+    // NYT has the lowest number and weight 0, so it is never swapped.
+    fn swap(&mut self, a: usize, b: usize) {
+        let (parent_a, parent_b) = (self.nodes[a].parent, self.nodes[b].parent);
+        self.nodes.swap(a, b);
+        self.nodes[a].parent = parent_a;
+        self.nodes[b].parent = parent_b;
+
+        for position in [a, b] {
+            let node = self.nodes[position];
+            if node.left == NONE {
+                self.leaf_of[node.byte as usize] = position;
+            } else {
+                self.nodes[node.left].parent = position;
+                self.nodes[node.right].parent = position;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(input: &[u8]) -> Vec<u8> {
+        let mut model = AdaptiveHuffman::new();
+        let mut writer = BitWriter::new(Vec::new());
+        for byte in input {
+            model.encode(*byte, &mut writer).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn decode(encoded: &[u8], len: usize) -> Vec<u8> {
+        let mut model = AdaptiveHuffman::new();
+        let mut reader = BitReader::new(encoded);
+        (0..len)
+            .map(|_| model.decode(&mut reader).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_first_byte_is_sent_raw() {
+        // the tree is only NYT, so its code is empty
+        assert_eq!(encode(b"a"), vec![b'a']);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: [&[u8]; 4] = [
+            b"abracadabra",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaab",
+            b"The quick brown fox jumps over the lazy dog\r\n",
+            &(0..=255u8).chain((0..=255u8).rev()).collect::<Vec<u8>>(),
+        ];
+        for input in inputs {
+            assert_eq!(decode(&encode(input), input.len()), input);
+        }
+    }
+
+    #[test]
+    fn test_adapts_to_skewed_input() {
+        let input: Vec<u8> = b"aaaaaaabaaaaaaac".repeat(500);
+        let encoded = encode(&input);
+
+        assert_eq!(decode(&encoded, input.len()), input);
+        // 'a' quickly gets a one bit code
+        assert!(encoded.len() < input.len() / 5);
+    }
+}
@@ -0,0 +1,244 @@
+// rANS (range asymmetric numeral systems) coding of a block's bytes
+//
+// Every byte gets a share of 2^PROB_BITS slots in proportion to its count,
+// and costs log2(2^PROB_BITS / share) bits, a fraction of a bit for very
+// common bytes, where a Huffman code takes at least one bit.
+//
+// The state is a u32 kept in [RANS_L, RANS_L << 8) by moving bytes in and
+// out of it. Bytes are encoded last to first so they decode first to last;
+// the payload starts with the final state, little endian, and decoding ends
+// back at RANS_L, which is checked.
+use std::collections::HashMap;
+
+use crate::error::CmError;
+
+pub const PROB_BITS: u32 = 15;
+const PROB_SCALE: u32 = 1 << PROB_BITS;
+const RANS_L: u32 = 1 << 23;
+
+// Slots of every byte, with the lookup table to decode them
+pub struct Frequencies {
+    freq: [u32; 256],
+    // First slot of every byte
+    start: [u32; 256],
+    // Byte owning every slot, only built for decoding
+    symbols: Vec<u8>,
+}
+
+impl Frequencies {
+    // Shares the slots between the counted bytes, each one gets at least one
+    pub fn new(char_map: &HashMap<u8, u64>) -> Self {
+        let total: u64 = char_map.values().sum();
+        let mut freq = [0u32; 256];
+        for (byte, count) in char_map {
+            if *count > 0 {
+                let share =
+                    (*count as u128 * PROB_SCALE as u128 + total as u128 / 2) / total as u128;
+                freq[*byte as usize] = (share as u32).max(1);
+            }
+        }
+
+        // Rounding leaves the sum a little off, take from or give to the
+        // largest shares, where it costs the least
+        let mut sum: u32 = freq.iter().sum();
+        while sum != PROB_SCALE && sum > 0 {
+            let largest = (0..256)
+                .max_by_key(|byte| (freq[*byte], std::cmp::Reverse(*byte)))
+                .expect("there are 256 bytes");
+            if sum > PROB_SCALE {
+                freq[largest] -= 1;
+                sum -= 1;
+            } else {
+                freq[largest] += 1;
+                sum += 1;
+            }
+        }
+
+        Self::with_freq(freq, false)
+    }
+
+    // Rebuilds the slots from a block header, refusing shares that do not add up
+    pub fn from_table(table: &HashMap<u8, u16>) -> Result<Self, CmError> {
+        let mut freq = [0u32; 256];
+        for (byte, share) in table {
+            freq[*byte as usize] = *share as u32;
+        }
+        let sum: u32 = freq.iter().sum();
+        if sum != PROB_SCALE || table.values().any(|share| *share == 0) {
+            return Err(CmError::format(
+                "archive is corrupt: frequency table does not add up",
+            ));
+        }
+        Ok(Self::with_freq(freq, true))
+    }
+
+    fn with_freq(freq: [u32; 256], lookup: bool) -> Self {
+        let mut start = [0u32; 256];
+        let mut next = 0;
+        for byte in 0..256 {
+            start[byte] = next;
+            next += freq[byte];
+        }
+        let mut symbols: Vec<u8> = Vec::new();
+        if lookup {
+            symbols.reserve(PROB_SCALE as usize);
+            for byte in 0..=255u8 {
+                symbols.extend(std::iter::repeat_n(byte, freq[byte as usize] as usize));
+            }
+        }
+        Frequencies {
+            freq,
+            start,
+            symbols,
+        }
+    }
+
+    // Shares as the block header stores them
+    pub fn table(&self) -> HashMap<u8, u16> {
+        (0..=255u8)
+            .filter(|byte| self.freq[*byte as usize] > 0)
+            .map(|byte| (byte, self.freq[byte as usize] as u16))
+            .collect()
+    }
+
+    // Bits needed to encode the counted bytes, None if a byte has no share
+    pub fn cost(&self, char_map: &HashMap<u8, u64>) -> Option<u64> {
+        let mut bits = 0f64;
+        for (byte, count) in char_map {
+            let freq = self.freq[*byte as usize];
+            if freq == 0 {
+                return None;
+            }
+            bits += *count as f64 * (PROB_BITS as f64 - (freq as f64).log2());
+        }
+        // and the final state
+        Some(bits.ceil() as u64 + 32)
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(data.len() / 2 + 4);
+        let mut x = RANS_L;
+        for byte in data.iter().rev() {
+            let freq = self.freq[*byte as usize];
+            let start = self.start[*byte as usize];
+            // Move bytes out until encoding keeps the state in range
+            let x_max = ((RANS_L >> PROB_BITS) << 8) * freq;
+            while x >= x_max {
+                out.push(x as u8);
+                x >>= 8;
+            }
+            x = ((x / freq) << PROB_BITS) + (x % freq) + start;
+        }
+        // Written backwards, so the state ends up first and little endian
+        out.extend_from_slice(&x.to_be_bytes());
+        out.reverse();
+        out
+    }
+
+    pub fn decode(
+        &self,
+        payload: &[u8],
+        raw_len: u32,
+        decoded: &mut Vec<u8>,
+    ) -> Result<(), CmError> {
+        let corrupt = || CmError::format("archive is corrupt: rANS payload does not decode");
+        let Some(state) = payload.first_chunk::<4>() else {
+            return Err(corrupt());
+        };
+        let mut x = u32::from_le_bytes(*state);
+        if !(RANS_L..RANS_L << 8).contains(&x) {
+            return Err(corrupt());
+        }
+
+        let mut pos = 4;
+        decoded.reserve(raw_len as usize);
+        for _ in 0..raw_len {
+            let slot = x & (PROB_SCALE - 1);
+            let byte = self.symbols[slot as usize];
+            x = self.freq[byte as usize] * (x >> PROB_BITS) + slot - self.start[byte as usize];
+            while x < RANS_L {
+                let Some(next) = payload.get(pos) else {
+                    return Err(corrupt());
+                };
+                x = (x << 8) | *next as u32;
+                pos += 1;
+            }
+            decoded.push(byte);
+        }
+
+        if x != RANS_L || pos != payload.len() {
+            return Err(corrupt());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let char_map = helpers::get_char_occurence(input).unwrap();
+        let encoded = Frequencies::new(&char_map).encode(input);
+        let table = Frequencies::new(&char_map).table();
+
+        let mut decoded: Vec<u8> = Vec::new();
+        Frequencies::from_table(&table)
+            .unwrap()
+            .decode(&encoded, input.len() as u32, &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, input);
+        encoded
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        round_trip(&text[..100_000]);
+        let all_bytes: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        round_trip(&all_bytes);
+        // only the state is left
+        assert_eq!(round_trip(&[b'x'; 1000]).len(), 4);
+    }
+
+    #[test]
+    fn test_beats_a_bit_per_byte_on_skewed_data() {
+        // mostly spaces, where Huffman needs at least 1000 bits
+        let mut input = vec![b' '; 1000];
+        input[500] = b'.';
+        input[900] = b'\n';
+        assert!(round_trip(&input).len() * 8 < 200);
+    }
+
+    #[test]
+    fn test_rare_bytes_keep_a_slot() {
+        let mut char_map = HashMap::from([(b'a', 1_000_000_000u64)]);
+        char_map.extend((128..=255u8).map(|byte| (byte, 1)));
+        let table = Frequencies::new(&char_map).table();
+        assert_eq!(table.len(), 129);
+        assert_eq!(
+            table.values().map(|share| *share as u32).sum::<u32>(),
+            PROB_SCALE
+        );
+    }
+
+    #[test]
+    fn test_rejects_corrupt_payloads() {
+        let input = b"abracadabra";
+        let char_map = helpers::get_char_occurence(&input[..]).unwrap();
+        let frequencies = Frequencies::new(&char_map);
+        let table = Frequencies::from_table(&frequencies.table()).unwrap();
+        let encoded = frequencies.encode(input);
+
+        let mut decoded: Vec<u8> = Vec::new();
+        assert!(table.decode(&encoded[..2], 11, &mut decoded).is_err());
+        let mut flipped = encoded.clone();
+        *flipped.last_mut().unwrap() ^= 0x10;
+        assert!(table.decode(&flipped, 11, &mut Vec::new()).is_err());
+
+        let mut uneven = frequencies.table();
+        *uneven.get_mut(&b'a').unwrap() += 1;
+        assert!(Frequencies::from_table(&uneven).is_err());
+    }
+}
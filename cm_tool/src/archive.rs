@@ -0,0 +1,651 @@
+// Multi-file archives, made by `pack` and read by `unpack` and `list`
+//
+// header
+//   magic        4 bytes  "CMHA"
+//   version      1 byte
+// entries
+//   the content of every file, each one a complete .huff stream
+// index, for every entry
+//   kind         1 byte   0 = file, 1 = directory
+//   path len     2 bytes  little endian
+//   path         UTF-8, relative to the packed directory, / separated
+//   size         8 bytes  original size in bytes
+//   mode         4 bytes  Unix permission bits
+//   mtime        8 bytes  seconds since the Unix epoch, signed
+//   offset       8 bytes  start of the entry's stream from the start of the archive
+//   length       8 bytes  length of the entry's stream
+// footer
+//   index offset 8 bytes
+//   entries      4 bytes  number of entries in the index
+//   magic        4 bytes  "CMHA"
+//
+// The index comes last so an archive can be written in one pass, and the
+// fixed size footer tells where to find it, so entries can be listed and
+// extracted one by one without reading the others. All numbers are little
+// endian.
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    decode::decode_stream,
+    encode::{EncodeOptions, encode_stream},
+    error::CmError,
+    helpers,
+};
+
+pub const MAGIC: [u8; 4] = *b"CMHA";
+pub const VERSION: u8 = 1;
+
+// magic + version
+const HEADER_LEN: u64 = 5;
+// index offset + entry count + magic
+const FOOTER_LEN: u64 = 8 + 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File = 0,
+    Directory = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    // Relative path, / separated
+    pub path: String,
+    // Original size in bytes, 0 for directories
+    pub size: u64,
+    pub mode: u32,
+    // Seconds since the Unix epoch
+    pub mtime: i64,
+    // Where the entry's .huff stream is in the archive
+    pub offset: u64,
+    pub compressed_len: u64,
+}
+
+// Writes an archive in one pass: entries first, then the index
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    // Bytes written so far, the offset of the next entry
+    position: u64,
+    entries: Vec<Entry>,
+    options: EncodeOptions,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, options: EncodeOptions) -> Result<Self, CmError> {
+        options.check()?;
+        // Unpacking has no way to be given one
+        if options.dictionary.is_some() {
+            return Err(CmError::InvalidOptions(
+                "multi-file archives are packed without a dictionary".to_string(),
+            ));
+        }
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(ArchiveWriter {
+            writer,
+            position: HEADER_LEN,
+            entries: Vec::new(),
+            options,
+        })
+    }
+
+    // Compresses the content of `reader` as the file at `path`
+    pub fn add_file<R: Read>(
+        &mut self,
+        path: &str,
+        mode: u32,
+        mtime: i64,
+        reader: R,
+    ) -> Result<(), CmError> {
+        let mut reader = helpers::ChecksumReader::new(reader);
+        let mut writer = helpers::CountingWriter {
+            inner: &mut self.writer,
+            count: 0,
+        };
+        encode_stream(&mut reader, &mut writer, &self.options)?;
+        let compressed_len = writer.count;
+        let (size, _) = reader.finish();
+
+        self.push(Entry {
+            kind: EntryKind::File,
+            path: path.to_string(),
+            size,
+            mode,
+            mtime,
+            offset: self.position,
+            compressed_len,
+        })
+    }
+
+    // Directories only have an index entry, so empty ones are kept too
+    pub fn add_dir(&mut self, path: &str, mode: u32, mtime: i64) -> Result<(), CmError> {
+        self.push(Entry {
+            kind: EntryKind::Directory,
+            path: path.to_string(),
+            size: 0,
+            mode,
+            mtime,
+            offset: self.position,
+            compressed_len: 0,
+        })
+    }
+
+    fn push(&mut self, entry: Entry) -> Result<(), CmError> {
+        check_path(&entry.path)?;
+        self.position += entry.compressed_len;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // Writes the index and the footer, returns the entries and the writer
+    pub fn finish(mut self) -> Result<(Vec<Entry>, W), CmError> {
+        let index_offset = self.position;
+        for entry in &self.entries {
+            write_entry(&mut self.writer, entry)?;
+        }
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer
+            .write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&MAGIC)?;
+        self.writer.flush()?;
+        Ok((self.entries, self.writer))
+    }
+}
+
+// Reads the index of an archive and extracts entries from it
+pub struct ArchiveReader<R: Read + Seek> {
+    reader: R,
+    entries: Vec<Entry>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, CmError> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader.read_exact(&mut header).map_err(CmError::truncated)?;
+        if header[..4] != MAGIC {
+            return Err(CmError::format("not a cm_tool multi-file archive"));
+        }
+        if header[4] != VERSION {
+            return Err(CmError::UnsupportedVersion(header[4]));
+        }
+
+        // The footer says where the index is
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+        if archive_len < HEADER_LEN + FOOTER_LEN {
+            return Err(CmError::format("archive is truncated"));
+        }
+        reader.seek(SeekFrom::Start(archive_len - FOOTER_LEN))?;
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        reader.read_exact(&mut footer)?;
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().expect("8 bytes"));
+        let count = u32::from_le_bytes(footer[8..12].try_into().expect("4 bytes"));
+        if footer[12..] != MAGIC {
+            return Err(CmError::format("archive is truncated"));
+        }
+        if index_offset < HEADER_LEN || index_offset > archive_len - FOOTER_LEN {
+            return Err(CmError::format("archive is corrupt: bad index offset"));
+        }
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let mut index = BufReader::new((&mut reader).take(archive_len - FOOTER_LEN - index_offset));
+        let mut entries: Vec<Entry> = Vec::new();
+        for _ in 0..count {
+            let entry = read_entry(&mut index)?;
+            if entry.offset < HEADER_LEN
+                || entry
+                    .offset
+                    .checked_add(entry.compressed_len)
+                    .is_none_or(|end| end > index_offset)
+            {
+                return Err(CmError::format(format!(
+                    "archive is corrupt: entry {} is outside of the archive",
+                    entry.path
+                )));
+            }
+            entries.push(entry);
+        }
+
+        Ok(ArchiveReader { reader, entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    // Decompresses a file entry into `writer`, checking its integrity
+    pub fn extract<W: Write>(&mut self, entry: &Entry, writer: W) -> Result<(), CmError> {
+        if entry.kind != EntryKind::File {
+            return Ok(());
+        }
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        let stream = BufReader::new((&mut self.reader).take(entry.compressed_len));
+        let summary = decode_stream(stream, writer)?;
+        if summary.trailer.original_len != entry.size {
+            return Err(CmError::format(format!(
+                "archive is corrupt: {} should be {} bytes, decoded {}",
+                entry.path, entry.size, summary.trailer.original_len
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Packs a directory (or a single file) into an archive at `output_path`
+pub fn pack(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    options: &EncodeOptions,
+    force: bool,
+) -> Result<Vec<Entry>, CmError> {
+    let metadata = fs::metadata(input_path).map_err(|e| helpers::with_path(e, input_path))?;
+
+    // Find the files before the output exists, so it is not packed into itself
+    let mut files: Vec<(PathBuf, String, Metadata)> = Vec::new();
+    if metadata.is_dir() {
+        let output = fs::canonicalize(output_path).ok();
+        walk(input_path, "", output.as_deref(), &mut files)?;
+    } else {
+        // A single file is stored under its own name
+        let name = input_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| CmError::InvalidOptions("input has no file name".to_string()))?;
+        files.push((input_path.clone(), name.to_string(), metadata));
+    }
+
+    let mut entries: Vec<Entry> = Vec::new();
+    helpers::write_output(output_path, force, |writer| {
+        let mut archive = ArchiveWriter::new(writer, options.clone())?;
+        for (file_path, path, metadata) in &files {
+            let (mode, mtime) = (mode_of(metadata), mtime_of(metadata));
+            if metadata.is_dir() {
+                archive.add_dir(path, mode, mtime)?;
+            } else {
+                let file = File::open(file_path).map_err(|e| helpers::with_path(e, file_path))?;
+                archive.add_file(path, mode, mtime, BufReader::new(file))?;
+            }
+        }
+        entries = archive.finish()?.0;
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+// Collects everything below `dir`, sorted by name so the archive does not
+// depend on the order the file system lists them in. Symbolic links and
+// other special files are skipped, and so is `output`.
+pub fn walk(
+    dir: &Path,
+    prefix: &str,
+    output: Option<&Path>,
+    files: &mut Vec<(PathBuf, String, Metadata)>,
+) -> Result<(), CmError> {
+    let mut children: Vec<fs::DirEntry> = fs::read_dir(dir)
+        .map_err(|e| helpers::with_path(e, dir))?
+        .collect::<io::Result<_>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let file_path = child.path();
+        let name = child.file_name();
+        let name = name.to_str().ok_or_else(|| {
+            helpers::with_path(
+                io::Error::new(ErrorKind::InvalidData, "file name is not valid UTF-8"),
+                &file_path,
+            )
+        })?;
+        let path = format!("{prefix}{name}");
+        let metadata = fs::symlink_metadata(&file_path)?;
+
+        if metadata.is_dir() {
+            files.push((file_path.clone(), path.clone(), metadata));
+            walk(&file_path, &format!("{path}/"), output, files)?;
+        } else if metadata.is_file()
+            && output
+                .is_none_or(|output| fs::canonicalize(&file_path).ok().as_deref() != Some(output))
+        {
+            files.push((file_path, path, metadata));
+        }
+    }
+    Ok(())
+}
+
+// Lists the entries of the archive at `input_path`
+pub fn list_archive(input_path: &PathBuf) -> Result<Vec<Entry>, CmError> {
+    let file = File::open(input_path).map_err(|e| helpers::with_path(e, input_path))?;
+    Ok(ArchiveReader::new(file)?.entries)
+}
+
+// Extracts the archive at `input_path` below `output_dir`, only the entries
+// in `selected` when it is not empty. Returns the extracted entries.
+pub fn unpack(
+    output_dir: &Path,
+    input_path: &PathBuf,
+    selected: &[String],
+    force: bool,
+) -> Result<Vec<Entry>, CmError> {
+    let file = File::open(input_path).map_err(|e| helpers::with_path(e, input_path))?;
+    let mut archive = ArchiveReader::new(file)?;
+
+    let entries: Vec<Entry> = archive
+        .entries()
+        .iter()
+        .filter(|entry| selected.is_empty() || selected.contains(&entry.path))
+        .cloned()
+        .collect();
+    if let Some(missing) = selected
+        .iter()
+        .find(|path| !entries.iter().any(|entry| &entry.path == *path))
+    {
+        return Err(CmError::InvalidOptions(format!(
+            "{missing} is not in the archive"
+        )));
+    }
+
+    for entry in &entries {
+        // Never write outside of the output directory
+        check_path(&entry.path)?;
+        let path = output_dir.join(&entry.path);
+
+        match entry.kind {
+            EntryKind::Directory => {
+                fs::create_dir_all(&path).map_err(|e| helpers::with_path(e, &path))?;
+            }
+            EntryKind::File => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| helpers::with_path(e, parent))?;
+                }
+                helpers::write_output(&path, force, |writer| archive.extract(entry, writer))?;
+                // The time first, the file may be read only afterwards
+                let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime.max(0) as u64);
+                File::options()
+                    .write(true)
+                    .open(&path)?
+                    .set_modified(mtime)?;
+                set_mode(&path, entry.mode)?;
+            }
+        }
+    }
+
+    // Directories last, deepest first, files could not go in read only ones
+    for entry in entries.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            set_mode(&output_dir.join(&entry.path), entry.mode)?;
+        }
+    }
+    Ok(entries)
+}
+
+// Paths must stay inside the directory they are extracted to
+fn check_path(path: &str) -> Result<(), CmError> {
+    let safe = !path.is_empty()
+        && path.len() <= u16::MAX as usize
+        && !path.contains('\\')
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !safe {
+        return Err(CmError::format(format!(
+            "archive is corrupt: unsafe entry path {path:?}"
+        )));
+    }
+    Ok(())
+}
+
+fn write_entry<W: Write>(writer: &mut W, entry: &Entry) -> io::Result<()> {
+    writer.write_all(&[entry.kind as u8])?;
+    writer.write_all(&(entry.path.len() as u16).to_le_bytes())?;
+    writer.write_all(entry.path.as_bytes())?;
+    writer.write_all(&entry.size.to_le_bytes())?;
+    writer.write_all(&entry.mode.to_le_bytes())?;
+    writer.write_all(&entry.mtime.to_le_bytes())?;
+    writer.write_all(&entry.offset.to_le_bytes())?;
+    writer.write_all(&entry.compressed_len.to_le_bytes())
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Entry, CmError> {
+    let kind = match read_array::<_, 1>(reader)?[0] {
+        0 => EntryKind::File,
+        1 => EntryKind::Directory,
+        kind => {
+            return Err(CmError::format(format!(
+                "archive is corrupt: unknown entry kind {kind}"
+            )));
+        }
+    };
+    let path_len = u16::from_le_bytes(read_array(reader)?);
+    let mut path = vec![0u8; path_len as usize];
+    reader.read_exact(&mut path).map_err(CmError::truncated)?;
+    let path = String::from_utf8(path)
+        .map_err(|_| CmError::format("archive is corrupt: entry path is not UTF-8"))?;
+
+    Ok(Entry {
+        kind,
+        path,
+        size: u64::from_le_bytes(read_array(reader)?),
+        mode: u32::from_le_bytes(read_array(reader)?),
+        mtime: i64::from_le_bytes(read_array(reader)?),
+        offset: u64::from_le_bytes(read_array(reader)?),
+        compressed_len: u64::from_le_bytes(read_array(reader)?),
+    })
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], CmError> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf).map_err(CmError::truncated)?;
+    Ok(buf)
+}
+
+fn mtime_of(metadata: &Metadata) -> i64 {
+    match metadata
+        .modified()
+        .map(|time| time.duration_since(UNIX_EPOCH))
+    {
+        Ok(Ok(since)) => since.as_secs() as i64,
+        // Before the epoch, or not supported by the platform
+        _ => 0,
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), CmError> {
+    use std::os::unix::fs::PermissionsExt;
+    // Only the permission bits, an archive must not make setuid, setgid or sticky files
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+        .map_err(|e| helpers::with_path(e, path))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), CmError> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions).map_err(|e| helpers::with_path(e, path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::Dictionary;
+    use std::{collections::HashMap, io::Cursor, sync::Arc};
+
+    // Writes an archive of `files` in memory
+    fn write_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = ArchiveWriter::new(Vec::new(), EncodeOptions::default()).unwrap();
+        archive.add_dir("logs", 0o755, 0).unwrap();
+        for (path, content) in files {
+            archive
+                .add_file(path, 0o644, 1_700_000_000, *content)
+                .unwrap();
+        }
+        archive.finish().unwrap().1
+    }
+
+    #[test]
+    fn test_entries_round_trip() {
+        let files: [(&str, &[u8]); 3] = [
+            ("logs/a.log", b"first file\n"),
+            ("logs/empty.log", b""),
+            ("b.bin", &[0, 255, 0, 255, 13, 10]),
+        ];
+        let bytes = write_archive(&files);
+
+        let mut archive = ArchiveReader::new(Cursor::new(bytes)).unwrap();
+        let entries = archive.entries().to_vec();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+
+        // extracted one by one, in any order
+        for (entry, (path, content)) in entries[1..].iter().zip(files).rev() {
+            assert_eq!(entry.path, path);
+            assert_eq!(entry.size, content.len() as u64);
+            assert_eq!(entry.mtime, 1_700_000_000);
+            let mut extracted: Vec<u8> = Vec::new();
+            archive.extract(entry, &mut extracted).unwrap();
+            assert_eq!(extracted, content);
+        }
+    }
+
+    #[test]
+    fn test_rejects_a_dictionary() {
+        let dictionary = Dictionary::from_counts(&HashMap::from([(b'a', 1)]));
+        let options = EncodeOptions {
+            dictionary: Some(Arc::new(dictionary)),
+            ..EncodeOptions::default()
+        };
+        let err = ArchiveWriter::new(Vec::new(), options).err().unwrap();
+        assert!(matches!(err, CmError::InvalidOptions(_)));
+    }
+
+    #[test]
+    fn test_rejects_unsafe_paths() {
+        for path in ["../evil", "/etc/passwd", "a/../../b", "", "a\\..\\b"] {
+            assert!(check_path(path).is_err(), "{path}");
+        }
+        assert!(check_path("logs/app/today.log").is_ok());
+
+        // an archive whose index was changed to point outside
+        let mut bytes = write_archive(&[("aa/evil", b"x")]);
+        let at = bytes.windows(7).position(|w| w == b"aa/evil").unwrap();
+        bytes[at..at + 7].copy_from_slice(b"../evil");
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("evil.huff");
+        fs::write(&archive, bytes).unwrap();
+
+        let err = unpack(&dir.path().join("out"), &archive, &[], false).unwrap_err();
+        assert!(err.to_string().contains("unsafe entry path"));
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_index() {
+        let bytes = write_archive(&[("a.log", b"some text")]);
+
+        let truncated = bytes[..bytes.len() - 3].to_vec();
+        assert!(ArchiveReader::new(Cursor::new(truncated)).is_err());
+
+        // entry offset past the index
+        let mut bad_offset = bytes.clone();
+        let index_offset =
+            u64::from_le_bytes(bytes[bytes.len() - 16..bytes.len() - 8].try_into().unwrap());
+        bad_offset[bytes.len() - 16..bytes.len() - 8]
+            .copy_from_slice(&(index_offset + 1).to_le_bytes());
+        assert!(ArchiveReader::new(Cursor::new(bad_offset)).is_err());
+
+        // a single file archive is not a multi-file one
+        let single = crate::compress(b"some text").unwrap();
+        assert!(ArchiveReader::new(Cursor::new(single)).is_err());
+    }
+
+    #[test]
+    fn test_pack_and_unpack_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("logs");
+        fs::create_dir_all(input.join("app")).unwrap();
+        fs::create_dir_all(input.join("empty")).unwrap();
+        fs::write(input.join("app/today.log"), b"one\r\ntwo\n").unwrap();
+        fs::write(input.join("top.log"), b"top").unwrap();
+        let old = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(input.join("top.log"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        set_mode(&input.join("top.log"), 0o600).unwrap();
+
+        let archive = dir.path().join("logs.huff");
+        let packed = pack(&archive, &input, &EncodeOptions::default(), false).unwrap();
+        let paths: Vec<&str> = packed.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["app", "app/today.log", "empty", "top.log"]);
+        assert_eq!(list_archive(&archive).unwrap(), packed);
+
+        let output = dir.path().join("out");
+        unpack(&output, &archive, &[], false).unwrap();
+        assert_eq!(
+            fs::read(output.join("app/today.log")).unwrap(),
+            b"one\r\ntwo\n"
+        );
+        assert!(output.join("empty").is_dir());
+        let metadata = fs::metadata(output.join("top.log")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), old);
+        assert_eq!(
+            mode_of(&metadata) & 0o777,
+            mode_of(&fs::metadata(input.join("top.log")).unwrap()) & 0o777
+        );
+
+        // or only some of the entries
+        let some = dir.path().join("some");
+        unpack(&some, &archive, &["top.log".to_string()], false).unwrap();
+        assert!(some.join("top.log").exists());
+        assert!(!some.join("app").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_drops_special_mode_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut archive = ArchiveWriter::new(Vec::new(), EncodeOptions::default()).unwrap();
+        archive.add_dir("shared", 0o1777, 0).unwrap();
+        archive
+            .add_file("shared/run", 0o6755, 0, &b"#!/bin/sh\n"[..])
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("special.huff");
+        fs::write(&path, archive.finish().unwrap().1).unwrap();
+
+        let output = dir.path().join("out");
+        unpack(&output, &path, &[], false).unwrap();
+        let mode = |path: &str| {
+            fs::metadata(output.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("shared"), 0o777);
+        assert_eq!(mode("shared/run"), 0o755);
+    }
+}
@@ -0,0 +1,290 @@
+// Benchmarks every coding mode on a set of files: compressed size, ratio,
+// bits per symbol against the order-0 entropy of the file, and encode and
+// decode throughput. Everything happens in memory so the disk is not timed,
+// and every mode has to give the file back byte for byte.
+use std::{
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    decode::decode_stream,
+    encode::{EncodeOptions, encode_stream},
+    error::CmError,
+    gzip::{gunzip_stream, gzip_stream},
+    header::Coder,
+    helpers,
+};
+
+// Level of the LZ77 and gzip modes, gzip's default
+const BENCH_LEVEL: u8 = 6;
+
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub file: String,
+    pub mode: &'static str,
+    pub original_len: u64,
+    pub compressed_len: u64,
+    // Shannon entropy of the file's bytes, in bits per byte
+    pub entropy: f64,
+    pub encode_time: Duration,
+    pub decode_time: Duration,
+}
+
+impl BenchResult {
+    // Compressed size over original size
+    pub fn ratio(&self) -> f64 {
+        self.compressed_len as f64 / self.original_len as f64
+    }
+
+    pub fn bits_per_symbol(&self) -> f64 {
+        self.compressed_len as f64 * 8.0 / self.original_len as f64
+    }
+
+    pub fn encode_mb_per_sec(&self) -> f64 {
+        mb_per_sec(self.original_len, self.encode_time)
+    }
+
+    pub fn decode_mb_per_sec(&self) -> f64 {
+        mb_per_sec(self.original_len, self.decode_time)
+    }
+}
+
+fn mb_per_sec(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
+
+// Every mode, from the block size and threads of `options`
+fn modes(options: &EncodeOptions) -> Vec<(&'static str, EncodeOptions)> {
+    let base = EncodeOptions {
+        block_size: options.block_size,
+        threads: options.threads,
+        ..EncodeOptions::default()
+    };
+    vec![
+        ("huffman", base.clone()),
+        (
+            "adaptive",
+            EncodeOptions {
+                adaptive: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "context",
+            EncodeOptions {
+                context: true,
+                ..base.clone()
+            },
+        ),
+        (
+            "lz77",
+            EncodeOptions {
+                level: BENCH_LEVEL,
+                ..base.clone()
+            },
+        ),
+        (
+            "ans",
+            EncodeOptions {
+                coder: Coder::Ans,
+                ..base.clone()
+            },
+        ),
+        (
+            "gzip",
+            EncodeOptions {
+                level: BENCH_LEVEL,
+                ..base
+            },
+        ),
+    ]
+}
+
+// Runs every mode on every file, in order
+pub fn bench(files: &[PathBuf], options: &EncodeOptions) -> Result<Vec<BenchResult>, CmError> {
+    let mut results: Vec<BenchResult> = Vec::new();
+    for file in files {
+        let data = fs::read(file).map_err(|e| helpers::with_path(e, file))?;
+        let entropy = helpers::entropy(&helpers::get_char_occurence(data.as_slice())?);
+
+        for (mode, options) in modes(options) {
+            let timer = Instant::now();
+            let mut encoded: Vec<u8> = Vec::new();
+            if mode == "gzip" {
+                gzip_stream(data.as_slice(), &mut encoded, &options)?;
+            } else {
+                encode_stream(data.as_slice(), &mut encoded, &options)?;
+            }
+            let encode_time = timer.elapsed();
+
+            let timer = Instant::now();
+            let mut decoded: Vec<u8> = Vec::with_capacity(data.len());
+            if mode == "gzip" {
+                gunzip_stream(encoded.as_slice(), &mut decoded)?;
+            } else {
+                decode_stream(encoded.as_slice(), &mut decoded)?;
+            }
+            let decode_time = timer.elapsed();
+
+            if decoded != data {
+                return Err(CmError::format(format!(
+                    "{mode} did not give {} back",
+                    file.display()
+                )));
+            }
+            results.push(BenchResult {
+                file: file.display().to_string(),
+                mode,
+                original_len: data.len() as u64,
+                compressed_len: encoded.len() as u64,
+                entropy,
+                encode_time,
+                decode_time,
+            });
+        }
+    }
+    Ok(results)
+}
+
+// One line per file and mode
+pub fn print_results(results: &[BenchResult]) {
+    let width = results
+        .iter()
+        .map(|result| result.file.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:<width$} {:<9} {:>12} {:>12} {:>8} {:>9} {:>8} {:>10} {:>10}",
+        "File",
+        "Mode",
+        "Size",
+        "Compressed",
+        "Ratio",
+        "Bits/sym",
+        "Entropy",
+        "Enc MB/s",
+        "Dec MB/s"
+    );
+    for result in results {
+        println!(
+            "{:<width$} {:<9} {:>12} {:>12} {:>8} {:>9} {:>8.4} {:>10} {:>10}",
+            result.file,
+            result.mode,
+            result.original_len,
+            result.compressed_len,
+            display_number(result.ratio() * 100.0, 2, "%"),
+            display_number(result.bits_per_symbol(), 4, ""),
+            result.entropy,
+            display_number(result.encode_mb_per_sec(), 1, ""),
+            display_number(result.decode_mb_per_sec(), 1, ""),
+        );
+    }
+}
+
+// Empty files have no ratio, and instant runs no throughput
+fn display_number(value: f64, decimals: usize, unit: &str) -> String {
+    if value.is_finite() {
+        format!("{value:.decimals$}{unit}")
+    } else {
+        "-".to_string()
+    }
+}
+
+// The results as a JSON array, one object per file and mode. Sizes are in
+// bytes and throughputs in MB/s (2^20 bytes); values that do not exist,
+// like the ratio of an empty file, are null.
+pub fn results_json(results: &[BenchResult]) -> String {
+    let mut json = String::from("[");
+    for (i, result) in results.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write!(
+            json,
+            "\n  {{\"file\": {}, \"mode\": {}, \"original_size\": {}, \"compressed_size\": {}, \
+             \"ratio\": {}, \"bits_per_symbol\": {}, \"entropy\": {}, \
+             \"encode_mb_per_sec\": {}, \"decode_mb_per_sec\": {}}}",
+            json_string(&result.file),
+            json_string(result.mode),
+            result.original_len,
+            result.compressed_len,
+            json_number(result.ratio()),
+            json_number(result.bits_per_symbol()),
+            json_number(result.entropy),
+            json_number(result.encode_mb_per_sec()),
+            json_number(result.decode_mb_per_sec()),
+        )
+        .expect("writing to a String cannot fail");
+    }
+    json.push_str("\n]");
+    json
+}
+
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{value:.6}")
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(json, "\\u{:04x}", c as u32).expect("writing to a String cannot fail")
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_every_mode() {
+        let file = PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/sample.txt"
+        ));
+        let results = bench(&[file], &EncodeOptions::default()).unwrap();
+        let modes: Vec<&str> = results.iter().map(|result| result.mode).collect();
+        assert_eq!(
+            modes,
+            ["huffman", "adaptive", "context", "lz77", "ans", "gzip"]
+        );
+        for result in &results {
+            assert!(result.ratio() < 1.0);
+            // order-0 coding cannot beat the entropy, matches can
+            if ["huffman", "adaptive", "ans"].contains(&result.mode) {
+                assert!(result.bits_per_symbol() >= result.entropy);
+            }
+        }
+
+        let json = results_json(&results);
+        assert!(json.starts_with("[\n  {\"file\": \""));
+        assert_eq!(json.matches("\"mode\"").count(), 6);
+        assert!(json.ends_with("}\n]"));
+    }
+
+    #[test]
+    fn test_json_values() {
+        assert_eq!(json_string("a \"b\"\\c\n\u{1}"), r#""a \"b\"\\c\n\u0001""#);
+        assert_eq!(json_number(0.5), "0.500000");
+        assert_eq!(json_number(f64::NAN), "null");
+        assert_eq!(results_json(&[]), "[\n]");
+    }
+}
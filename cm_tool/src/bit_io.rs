@@ -0,0 +1,404 @@
+// Streaming bit level reader and writer
+// Bits are packed most significant bit first, the same order the codes are read in,
+// except for the Lsb ones used for DEFLATE streams
+use std::io::{self, ErrorKind, Read, Write};
+
+// Size of the chunks read from and written to the underlying stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct BitWriter<W: Write> {
+    inner: W,
+    // Packed bytes waiting to be written out
+    buf: Vec<u8>,
+    // Bits not yet making up a full byte, kept in the low bits
+    acc: u64,
+    acc_len: u8,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BitWriter {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            acc: 0,
+            acc_len: 0,
+        }
+    }
+
+    // Writes the lowest `len` bits of `value`, most significant first
+    pub fn write_bits(&mut self, value: u64, len: u8) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        // Keep the accumulator from overflowing on long codes
+        if len > 32 {
+            self.write_bits(value >> 32, len - 32)?;
+            return self.write_bits(value, 32);
+        }
+        self.acc = (self.acc << len) | (value & (u64::MAX >> (64 - len)));
+        self.acc_len += len;
+
+        // Move every full byte into the buffer
+        while self.acc_len >= 8 {
+            self.acc_len -= 8;
+            self.buf.push((self.acc >> self.acc_len) as u8);
+        }
+
+        if self.buf.len() >= CHUNK_SIZE {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Pads the last byte with zeros, writes everything out and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.acc_len > 0 {
+            self.buf.push((self.acc << (8 - self.acc_len)) as u8);
+            self.acc_len = 0;
+        }
+        self.inner.write_all(&self.buf)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub struct BitReader<R: Read> {
+    inner: R,
+    // Chunk of bytes read from the stream
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    // Next bits of the stream, kept in the high bits
+    acc: u64,
+    acc_len: u8,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: vec![0u8; CHUNK_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+            acc: 0,
+            acc_len: 0,
+        }
+    }
+
+    // Reads the next bit, fails with UnexpectedEof when the stream runs out
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        let bit = self.peek_bits(1)?;
+        self.consume(1)?;
+        Ok(bit == 1)
+    }
+
+    // Returns the next `n` (at most 56) bits without consuming them.
+    // Past the end of the stream the missing bits read as zeros.
+    pub fn peek_bits(&mut self, n: u8) -> io::Result<u64> {
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.acc_len < n {
+            self.refill()?;
+        }
+        Ok(self.acc >> (64 - n))
+    }
+
+    // Drops `n` bits that were looked at with peek_bits
+    pub fn consume(&mut self, n: u8) -> io::Result<()> {
+        if n > self.acc_len {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "encoded data ended early",
+            ));
+        }
+        self.acc = if n == 64 { 0 } else { self.acc << n };
+        self.acc_len -= n;
+        Ok(())
+    }
+
+    // Tops the accumulator up to at least 57 bits, unless the stream ends
+    fn refill(&mut self) -> io::Result<()> {
+        while self.acc_len <= 56 {
+            if self.pos == self.len {
+                if self.eof {
+                    break;
+                }
+                self.fill()?;
+                continue;
+            }
+            self.acc |= (self.buf[self.pos] as u64) << (56 - self.acc_len);
+            self.pos += 1;
+            self.acc_len += 8;
+        }
+        Ok(())
+    }
+
+    // Reads the next chunk of the stream into the buffer
+    fn fill(&mut self) -> io::Result<()> {
+        loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(read) => {
+                    self.pos = 0;
+                    self.len = read;
+                    self.eof = read == 0;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// DEFLATE packs bits the other way round, least significant bit first:
+// values go in as they are and Huffman codes are reversed before writing
+pub struct LsbBitWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    // Bits not yet making up a full byte, kept in the low bits
+    acc: u64,
+    acc_len: u8,
+}
+
+impl<W: Write> LsbBitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        LsbBitWriter {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            acc: 0,
+            acc_len: 0,
+        }
+    }
+
+    // Writes the lowest `len` (at most 32) bits of `value`, least significant first
+    pub fn write_bits(&mut self, value: u64, len: u8) -> io::Result<()> {
+        debug_assert!(len <= 32);
+        self.acc |= (value & ((1u64 << len) - 1)) << self.acc_len;
+        self.acc_len += len;
+        while self.acc_len >= 8 {
+            self.buf.push(self.acc as u8);
+            self.acc >>= 8;
+            self.acc_len -= 8;
+        }
+
+        if self.buf.len() >= CHUNK_SIZE {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Pads the current byte with zeros
+    pub fn align(&mut self) {
+        if self.acc_len > 0 {
+            self.buf.push(self.acc as u8);
+            self.acc = 0;
+            self.acc_len = 0;
+        }
+    }
+
+    // Writes whole bytes, the writer must be aligned
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(self.acc_len, 0);
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Pads the last byte, writes everything out and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.align();
+        self.inner.write_all(&self.buf)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+// Reads bits least significant first, as DEFLATE stores them
+pub struct LsbBitReader<R: Read> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    // Next bits of the stream, kept in the low bits
+    acc: u64,
+    acc_len: u8,
+}
+
+impl<R: Read> LsbBitReader<R> {
+    pub fn new(inner: R) -> Self {
+        LsbBitReader {
+            inner,
+            buf: vec![0u8; CHUNK_SIZE].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+            acc: 0,
+            acc_len: 0,
+        }
+    }
+
+    // Returns the next `n` (at most 32) bits without consuming them.
+    // Past the end of the stream the missing bits read as zeros.
+    pub fn peek_bits(&mut self, n: u8) -> io::Result<u64> {
+        if self.acc_len < n {
+            self.refill()?;
+        }
+        Ok(self.acc & ((1u64 << n) - 1))
+    }
+
+    // Drops `n` bits that were looked at with peek_bits
+    pub fn consume(&mut self, n: u8) -> io::Result<()> {
+        if n > self.acc_len {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "encoded data ended early",
+            ));
+        }
+        self.acc >>= n;
+        self.acc_len -= n;
+        Ok(())
+    }
+
+    // Reads the next `n` (at most 32) bits
+    pub fn read_bits(&mut self, n: u8) -> io::Result<u64> {
+        let bits = self.peek_bits(n)?;
+        self.consume(n)?;
+        Ok(bits)
+    }
+
+    // Skips to the start of the next byte
+    pub fn align(&mut self) {
+        let partial = self.acc_len % 8;
+        self.acc >>= partial;
+        self.acc_len -= partial;
+    }
+
+    // True once every bit of the stream has been read
+    pub fn at_end(&mut self) -> io::Result<bool> {
+        if self.acc_len == 0 {
+            self.refill()?;
+        }
+        Ok(self.acc_len == 0)
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        while self.acc_len <= 56 {
+            if self.pos == self.len {
+                if self.eof {
+                    break;
+                }
+                self.fill()?;
+                continue;
+            }
+            self.acc |= (self.buf[self.pos] as u64) << self.acc_len;
+            self.pos += 1;
+            self.acc_len += 8;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(read) => {
+                    self.pos = 0;
+                    self.len = read;
+                    self.eof = read == 0;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits_round_trip() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b0110, 4).unwrap();
+        writer.write_bits(0b101, 3).unwrap();
+        writer.write_bits(0b11, 2).unwrap();
+        writer.write_bits(u64::MAX, 64).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        // 1 0110 101 | 11 + 64 ones + padding
+        assert_eq!(bytes[0], 0b1011_0101);
+        assert_eq!(&bytes[1..9], &[0xff; 8]);
+        assert_eq!(bytes[9], 0b1100_0000);
+
+        let mut reader = BitReader::new(bytes.as_slice());
+        let bits: Vec<bool> = (0..10).map(|_| reader.read_bit().unwrap()).collect();
+        let expected = [
+            true, false, true, true, false, true, false, true, true, true,
+        ];
+        assert_eq!(bits, expected);
+    }
+
+    #[test]
+    fn test_reader_reports_truncated_data() {
+        let mut reader = BitReader::new([0xffu8].as_slice());
+        for _ in 0..8 {
+            assert!(reader.read_bit().unwrap());
+        }
+        let err = reader.read_bit().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_peek_and_consume() {
+        let bytes = [0b1010_1100u8, 0b0101_0011, 0xff];
+        let mut reader = BitReader::new(bytes.as_slice());
+
+        // peeking does not move the reader
+        assert_eq!(reader.peek_bits(4).unwrap(), 0b1010);
+        assert_eq!(reader.peek_bits(12).unwrap(), 0b1010_1100_0101);
+        reader.consume(6).unwrap();
+        assert_eq!(reader.peek_bits(6).unwrap(), 0b00_0101);
+        reader.consume(18).unwrap();
+
+        // the stream is exhausted, peeks are zero padded but consuming fails
+        assert_eq!(reader.peek_bits(8).unwrap(), 0);
+        assert!(reader.consume(1).is_err());
+    }
+
+    #[test]
+    fn test_lsb_bits_round_trip() {
+        let mut writer = LsbBitWriter::new(Vec::new());
+        writer.write_bits(0b1, 1).unwrap();
+        writer.write_bits(0b10, 2).unwrap();
+        writer.write_bits(0b11001, 5).unwrap();
+        writer.write_bits(0b101, 3).unwrap();
+        writer.align();
+        writer.write_bytes(&[0xab]).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        // first value in the lowest bits
+        assert_eq!(bytes, [0b1100_1101, 0b0000_0101, 0xab]);
+
+        let mut reader = LsbBitReader::new(bytes.as_slice());
+        assert_eq!(reader.read_bits(1).unwrap(), 0b1);
+        assert_eq!(reader.read_bits(2).unwrap(), 0b10);
+        assert_eq!(reader.read_bits(5).unwrap(), 0b11001);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        reader.align();
+        assert!(!reader.at_end().unwrap());
+        assert_eq!(reader.read_bits(8).unwrap(), 0xab);
+        assert!(reader.at_end().unwrap());
+        assert!(reader.read_bits(1).is_err());
+    }
+}
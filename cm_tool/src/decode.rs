@@ -1,44 +1,1037 @@
-use std::{collections::HashMap, fs::{ OpenOptions}, io::{ Write}, path::PathBuf};
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
 
-use bitvec::{order::Msb0, vec::BitVec, view::BitView};
+use crc32fast::Hasher;
 
-use crate::{EncodedData, helpers::{read_file}};
+use crate::{
+    adaptive::AdaptiveHuffman,
+    ans::Frequencies,
+    bit_io::BitReader,
+    decode_table::DecodeTable,
+    dictionary::Dictionary,
+    error::CmError,
+    gzip::{self, gunzip_stream},
+    header::{BlockHeader, BlockKind, Coder, Header, LzLengths, SeekIndex, Trailer},
+    helpers::{self, CountingReader},
+    lz77,
+};
 
-pub fn decode_and_output_file(output_path: &PathBuf, input_path: &PathBuf) {
-    // Open the file
-    let file_content = read_file(input_path);
+// `dictionary` is the one the archive was encoded with, if any
+pub fn decode_and_output_file(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    dictionary: Option<Arc<Dictionary>>,
+    force: bool,
+) -> Result<(), CmError> {
+    // Open the file, or stdin
+    let mut input = helpers::open_input(input_path)?;
+    let magic = read_magic(&mut input)?;
+    let is_gzip = magic == gzip::MAGIC;
+    let reader = BufReader::new(io::Cursor::new(magic).chain(input));
 
-    // decode the struct 
-    let decoded_struct : EncodedData = rmp_serde::from_slice(&file_content).expect("Failed to decode struct");
+    // Write to the output (or stdout) as the bytes are decoded, a file
+    // only shows up once the whole archive is decoded and checked
+    helpers::write_output(output_path, force, |writer| {
+        if is_gzip {
+            gunzip_stream(reader, writer)?;
+        } else {
+            decode_stream_with_dictionary(reader, writer, dictionary)?;
+        }
+        Ok(())
+    })
+}
+
+// The first bytes of `reader`, as many as the gzip magic number unless it
+// ends before. A pipe may give them one at a time.
+fn read_magic<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut magic: Vec<u8> = Vec::new();
+    reader
+        .by_ref()
+        .take(gzip::MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+// What decoding an archive found out about it
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub header: Header,
+    pub trailer: Trailer,
+    pub blocks: u64,
+    // Number of code tables sent, the other blocks reused the previous one
+    pub tables: u64,
+    // Code lengths of the first table
+    pub lengths: Option<HashMap<u8, u8>>,
+    // Number of blocks coded with adaptive Huffman
+    pub adaptive_blocks: u64,
+    // Number of blocks coded with order-1 context tables
+    pub context_blocks: u64,
+    // Number of blocks coded with LZ77 matches
+    pub lz_blocks: u64,
+    // Number of blocks coded with the table of a dictionary
+    pub dictionary_blocks: u64,
+}
+
+// Decompresses a .huff archive read from `reader`, one block at a time.
+//
+// The integrity of the archive is checked once its end is reached: a
+// corrupt archive makes `read` fail rather than return end of file.
+pub struct HuffmanDecoder<R: Read> {
+    reader: CountingReader<R>,
+    header: Option<Header>,
+    state: BlockState,
+    // Where the blocks were, checked against the seek index at the end
+    index: SeekIndex,
+    payload: Vec<u8>,
+    // Current block, handed out from `position` on
+    decoded: Vec<u8>,
+    position: usize,
+    // Size and checksum of everything decoded so far
+    decoded_len: u64,
+    hasher: Hasher,
+    first_lengths: Option<HashMap<u8, u8>>,
+    blocks: u64,
+    tables: u64,
+    adaptive_blocks: u64,
+    context_blocks: u64,
+    lz_blocks: u64,
+    dictionary_blocks: u64,
+    // Set once the trailer has been read and checked
+    trailer: Option<Trailer>,
+}
+
+impl<R: Read> HuffmanDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_dictionary(reader, None)
+    }
+
+    // Decodes archives whose blocks may use the tables of `dictionary`
+    pub fn with_dictionary(reader: R, dictionary: Option<Arc<Dictionary>>) -> Self {
+        HuffmanDecoder {
+            reader: CountingReader {
+                inner: reader,
+                count: 0,
+            },
+            header: None,
+            state: BlockState::new(dictionary),
+            index: SeekIndex::default(),
+            payload: Vec::new(),
+            decoded: Vec::new(),
+            position: 0,
+            decoded_len: 0,
+            hasher: Hasher::new(),
+            first_lengths: None,
+            blocks: 0,
+            tables: 0,
+            adaptive_blocks: 0,
+            context_blocks: 0,
+            lz_blocks: 0,
+            dictionary_blocks: 0,
+            trailer: None,
+        }
+    }
+
+    // What decoding found out about the archive, once all of it was read
+    pub fn summary(&self) -> Option<Summary> {
+        Some(Summary {
+            header: self.header.clone()?,
+            trailer: self.trailer.clone()?,
+            blocks: self.blocks,
+            tables: self.tables,
+            lengths: self.first_lengths.clone(),
+            adaptive_blocks: self.adaptive_blocks,
+            context_blocks: self.context_blocks,
+            lz_blocks: self.lz_blocks,
+            dictionary_blocks: self.dictionary_blocks,
+        })
+    }
+
+    // Decodes the next block into `decoded`, or reads and checks the trailer
+    fn next_block(&mut self) -> Result<(), CmError> {
+        let header = match &self.header {
+            Some(header) => header,
+            None => self.header.insert(Header::read(&mut self.reader)?),
+        };
+
+        let offset = self.reader.count;
+        let block = BlockHeader::read(&mut self.reader, header.coder)?;
+        if block.kind == BlockKind::End {
+            // Check the decoded bytes against what was encoded
+            let trailer = Trailer::read(&mut self.reader)?;
+            verify(&trailer, self.decoded_len, self.hasher.clone().finalize())?;
+            if header.seek_index() {
+                self.check_index()?;
+            }
+            self.trailer = Some(trailer);
+            return Ok(());
+        }
+        check_block_sizes(header, &block)?;
+        self.index.push(&block, offset);
+
+        // Build the lookup table from the canonical code lengths,
+        // or keep the previous one
+        self.state.take_table(&block)?;
+        if let Some(lengths) = &block.lengths {
+            self.first_lengths.get_or_insert_with(|| lengths.clone());
+        }
+        if block.lengths.is_some() || block.frequencies.is_some() {
+            self.tables += 1;
+        }
+
+        read_payload(&mut self.reader, &block, &mut self.payload)?;
+
+        self.decoded.clear();
+        self.position = 0;
+        self.state
+            .decode(header.coder, &block, &self.payload, &mut self.decoded)?;
+        match block.kind {
+            BlockKind::Adaptive => self.adaptive_blocks += 1,
+            BlockKind::Context => self.context_blocks += 1,
+            BlockKind::Lz77 => self.lz_blocks += 1,
+            BlockKind::Dictionary => self.dictionary_blocks += 1,
+            _ => {}
+        }
+        self.blocks += 1;
+
+        self.hasher.update(&self.decoded);
+        self.decoded_len += self.decoded.len() as u64;
+        Ok(())
+    }
+
+    // The seek index must list the blocks that were just decoded
+    fn check_index(&mut self) -> Result<(), CmError> {
+        let index_offset = self.reader.count;
+        let blocks = self.index.entries.len() as u32;
+        let index = SeekIndex::read(&mut self.reader, blocks)?;
+        let footer = SeekIndex::read_footer(&mut self.reader)?;
+        if index.entries != self.index.entries || footer != (index_offset, blocks) {
+            return Err(CmError::format(
+                "archive is corrupt: seek index does not match the blocks",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Tables carried from one block to the next: the last code or frequency
+// table sent, and the adaptive model, along with the dictionary's tables
+pub struct BlockState {
+    table: Option<DecodeTable<u8>>,
+    frequencies: Option<Frequencies>,
+    model: AdaptiveHuffman,
+    dictionary: Option<Arc<Dictionary>>,
+    // Built on the first block using the dictionary
+    dictionary_tables: Option<(DecodeTable<u8>, Frequencies)>,
+}
+
+impl BlockState {
+    pub fn new(dictionary: Option<Arc<Dictionary>>) -> Self {
+        BlockState {
+            table: None,
+            frequencies: None,
+            model: AdaptiveHuffman::new(),
+            dictionary,
+            dictionary_tables: None,
+        }
+    }
+
+    // Forgets the blocks decoded so far, to start over from another one
+    pub fn reset(&mut self) {
+        self.table = None;
+        self.frequencies = None;
+        self.model = AdaptiveHuffman::new();
+    }
+
+    // Keeps the table a block sends, for it and the blocks reusing it
+    pub fn take_table(&mut self, block: &BlockHeader) -> Result<(), CmError> {
+        if let Some(lengths) = &block.lengths {
+            self.table = Some(DecodeTable::new(lengths));
+        }
+        if let Some(frequencies) = &block.frequencies {
+            self.frequencies = Some(Frequencies::from_table(frequencies)?);
+        }
+        if let Some(id) = block.dictionary {
+            let Some(dictionary) = &self.dictionary else {
+                return Err(CmError::InvalidOptions(format!(
+                    "the archive was encoded with dictionary {id:08x}, decode it with --dict"
+                )));
+            };
+            if dictionary.id != id {
+                return Err(CmError::InvalidOptions(format!(
+                    "the archive was encoded with dictionary {id:08x}, not {:08x}",
+                    dictionary.id
+                )));
+            }
+            if self.dictionary_tables.is_none() {
+                self.dictionary_tables = Some((
+                    DecodeTable::new(&dictionary.lengths),
+                    Frequencies::from_table(&dictionary.frequencies)?,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Decodes a block's payload into `decoded`, once its table was taken
+    pub fn decode(
+        &mut self,
+        coder: Coder,
+        block: &BlockHeader,
+        payload: &[u8],
+        decoded: &mut Vec<u8>,
+    ) -> Result<(), CmError> {
+        if block.kind == BlockKind::Adaptive {
+            decode_adaptive_block(&mut self.model, payload, block.raw_len, decoded)
+        } else if block.kind == BlockKind::Dictionary {
+            let (table, frequencies) = self
+                .dictionary_tables
+                .as_ref()
+                .expect("taken with the block header");
+            match coder {
+                Coder::Huffman => decode_block(table, payload, block.raw_len, decoded),
+                Coder::Ans => frequencies.decode(payload, block.raw_len, decoded),
+            }
+        } else if let Some(contexts) = &block.contexts {
+            decode_context_block(contexts, payload, block.raw_len, decoded)
+        } else if let Some(lz) = &block.lz {
+            decode_lz_block(lz, payload, block.raw_len, decoded)
+        } else if coder == Coder::Ans {
+            let Some(frequencies) = &self.frequencies else {
+                return Err(CmError::format(
+                    "archive is corrupt: block reuses a frequency table before any was sent",
+                ));
+            };
+            frequencies.decode(payload, block.raw_len, decoded)
+        } else {
+            let Some(table) = &self.table else {
+                return Err(CmError::format(
+                    "archive is corrupt: block reuses a code table before any was sent",
+                ));
+            };
+            decode_block(table, payload, block.raw_len, decoded)
+        }
+    }
+}
+
+impl<R: Read> Read for HuffmanDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Blocks can be empty, keep going until there is something to hand out
+        while self.position == self.decoded.len() {
+            if self.trailer.is_some() || buf.is_empty() {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+
+        let len = buf.len().min(self.decoded.len() - self.position);
+        buf[..len].copy_from_slice(&self.decoded[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+// Decodes a whole archive from `reader` into `writer` one block at a time,
+// checking its integrity. Returns a summary so callers can report on the archive.
+pub fn decode_stream<R: Read, W: Write>(reader: R, writer: W) -> Result<Summary, CmError> {
+    decode_stream_with_dictionary(reader, writer, None)
+}
+
+// Same for archives whose blocks may use the tables of `dictionary`
+pub fn decode_stream_with_dictionary<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    dictionary: Option<Arc<Dictionary>>,
+) -> Result<Summary, CmError> {
+    let mut decoder = HuffmanDecoder::with_dictionary(reader, dictionary);
+    io::copy(&mut decoder, &mut writer)?;
+    Ok(decoder
+        .summary()
+        .expect("the decoder only reports the end of the archive after its trailer"))
+}
+
+// Decodes the `raw_len` bytes packed in a block's payload
+fn decode_block(
+    table: &DecodeTable<u8>,
+    payload: &[u8],
+    raw_len: u32,
+    decoded: &mut Vec<u8>,
+) -> Result<(), CmError> {
+    let mut bits = BitReader::new(payload);
+    decoded.reserve(raw_len as usize);
+    for _ in 0..raw_len {
+        let (byte, _) = table.decode(&mut bits).map_err(corrupt_payload)?;
+        decoded.push(byte);
+    }
+    Ok(())
+}
+
+// Same for a block coded with a table per preceding byte, starting from context 0
+fn decode_context_block(
+    contexts: &[(u8, HashMap<u8, u8>)],
+    payload: &[u8],
+    raw_len: u32,
+    decoded: &mut Vec<u8>,
+) -> Result<(), CmError> {
+    let mut tables: Vec<Option<DecodeTable<u8>>> = (0..256).map(|_| None).collect();
+    for (context, lengths) in contexts {
+        tables[*context as usize] = Some(DecodeTable::new(lengths));
+    }
+
+    let mut bits = BitReader::new(payload);
+    decoded.reserve(raw_len as usize);
+    let mut context = 0usize;
+    for _ in 0..raw_len {
+        let Some(table) = &tables[context] else {
+            return Err(CmError::format(format!(
+                "archive is corrupt: no code table for context {context}"
+            )));
+        };
+        let (byte, _) = table.decode(&mut bits).map_err(corrupt_payload)?;
+        decoded.push(byte);
+        context = byte as usize;
+    }
+    Ok(())
+}
+
+// Same for an LZ77 block: literals are pushed, matches copy from the bytes
+// already decoded in the block, which may overlap the bytes being copied
+fn decode_lz_block(
+    lz: &LzLengths,
+    payload: &[u8],
+    raw_len: u32,
+    decoded: &mut Vec<u8>,
+) -> Result<(), CmError> {
+    let literals = DecodeTable::new(&lz.literals);
+    let distances = DecodeTable::new(&lz.distances);
+
+    let mut bits = BitReader::new(payload);
+    let raw_len = raw_len as usize;
+    decoded.reserve(raw_len);
+    while decoded.len() < raw_len {
+        let (symbol, _) = literals.decode(&mut bits).map_err(corrupt_payload)?;
+        if let Ok(byte) = u8::try_from(symbol) {
+            decoded.push(byte);
+            continue;
+        }
+        let Some((base, extra_len)) = lz77::length_base(symbol) else {
+            return Err(CmError::format(format!(
+                "archive is corrupt: unexpected symbol {symbol}"
+            )));
+        };
+        let length = base as usize + read_extra(&mut bits, extra_len)?;
+
+        if lz.distances.is_empty() {
+            return Err(CmError::format(
+                "archive is corrupt: match in a block without distance codes",
+            ));
+        }
+        let (symbol, _) = distances.decode(&mut bits).map_err(corrupt_payload)?;
+        let (base, extra_len) =
+            lz77::distance_base(symbol).expect("checked when reading the table");
+        let distance = base as usize + read_extra(&mut bits, extra_len)?;
+
+        if distance > decoded.len() || decoded.len() + length > raw_len {
+            return Err(CmError::format(
+                "archive is corrupt: match outside of the block",
+            ));
+        }
+        let start = decoded.len() - distance;
+        for i in start..start + length {
+            decoded.push(decoded[i]);
+        }
+    }
+    Ok(())
+}
+
+// Extra bits of a length or distance, most significant first
+fn read_extra<R: Read>(bits: &mut BitReader<R>, len: u8) -> Result<usize, CmError> {
+    let value = bits.peek_bits(len).map_err(corrupt_payload)?;
+    bits.consume(len).map_err(corrupt_payload)?;
+    Ok(value as usize)
+}
+
+// Same for a block coded with adaptive Huffman, `model` carries on to the next block
+fn decode_adaptive_block(
+    model: &mut AdaptiveHuffman,
+    payload: &[u8],
+    raw_len: u32,
+    decoded: &mut Vec<u8>,
+) -> Result<(), CmError> {
+    let mut bits = BitReader::new(payload);
+    decoded.reserve(raw_len as usize);
+    for _ in 0..raw_len {
+        decoded.push(model.decode(&mut bits).map_err(corrupt_payload)?);
+    }
+    Ok(())
+}
+
+// The payload is already in memory, so failing to read it means it is corrupt
+fn corrupt_payload(e: io::Error) -> CmError {
+    CmError::format(format!("archive is corrupt: {e}"))
+}
+
+// Reads a block's payload into `payload`. The buffer grows with the bytes
+// actually read, not with the length a corrupt header claims.
+pub fn read_payload<R: Read>(
+    reader: &mut R,
+    block: &BlockHeader,
+    payload: &mut Vec<u8>,
+) -> Result<(), CmError> {
+    payload.clear();
+    reader
+        .by_ref()
+        .take(block.payload_len as u64)
+        .read_to_end(payload)?;
+    if payload.len() < block.payload_len as usize {
+        return Err(CmError::format("archive is truncated"));
+    }
+    Ok(())
+}
+
+// Refuses blocks bigger than the archive says they can be
+pub fn check_block_sizes(header: &Header, block: &BlockHeader) -> Result<(), CmError> {
+    // static codes are at most 64 bits, adaptive ones at most 256 plus a raw byte
+    let max_bits_per_byte: u64 = match block.kind {
+        BlockKind::Adaptive => 256 + 8,
+        _ => 64,
+    };
+    let max_payload = (block.raw_len as u64 * max_bits_per_byte).div_ceil(8);
+    if block.raw_len > header.block_size || block.payload_len as u64 > max_payload {
+        return Err(CmError::format(
+            "archive is corrupt: block is larger than the block size",
+        ));
+    }
+    Ok(())
+}
+
+// Compares the decoded length and checksum with the ones stored in the trailer
+pub fn verify(trailer: &Trailer, decoded_len: u64, checksum: u32) -> Result<(), CmError> {
+    if decoded_len != trailer.original_len {
+        return Err(CmError::format(format!(
+            "archive is corrupt: expected {} bytes, decoded {}",
+            trailer.original_len, decoded_len
+        )));
+    }
+    if checksum != trailer.checksum {
+        return Err(CmError::Checksum {
+            expected: trailer.checksum,
+            actual: checksum,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode::{EncodeOptions, encode_and_output_file, encode_stream},
+        generate_huffman_codes::canonical_codes,
+    };
+    use std::{
+        fs::{read, remove_file},
+        time::{Duration, Instant},
+    };
+
+    // Encodes then decodes `input` through temp files and returns the decoded bytes
+    fn round_trip(name: &str, input: &PathBuf) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let encoded = dir.join(format!("cm_tool_{name}.huff"));
+        let decoded = dir.join(format!("cm_tool_{name}.out"));
+        let _ = remove_file(&decoded);
+
+        encode_and_output_file(&encoded, input, &EncodeOptions::default(), true)
+            .expect("Failed to encode");
+        decode_and_output_file(&decoded, &encoded, None, false).expect("Failed to decode");
+
+        let bytes = read(&decoded).expect("Unable to read decoded file");
+        let _ = remove_file(&encoded);
+        let _ = remove_file(&decoded);
+        bytes
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let input = PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/sample.bin"
+        ));
+
+        let original = read(&input).expect("Unable to read fixture");
+        // the fixture is gzip data, which is not valid UTF-8
+        assert!(String::from_utf8(original.clone()).is_err());
+
+        assert_eq!(round_trip("binary_round_trip", &input), original);
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        // in memory streams, as used for stdin and stdout
+        let input: &[u8] = b"tar c dir | cm_tool encode - - > dir.huff\r\n\x00\xff";
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input, &mut encoded, &EncodeOptions::default()).unwrap();
+
+        let mut decoded: Vec<u8> = Vec::new();
+        decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_decoder_reads_in_small_pieces() {
+        let input: Vec<u8> = b"read back one byte at a time".repeat(40);
+        let encoded = encode_blocks(&input, 100);
+
+        let mut decoder = HuffmanDecoder::new(encoded.as_slice());
+        let mut decoded: Vec<u8> = Vec::new();
+        let mut byte = [0u8; 1];
+        while decoder.read(&mut byte).unwrap() == 1 {
+            decoded.push(byte[0]);
+        }
+        assert_eq!(decoded, input);
+        assert_eq!(decoder.summary().unwrap().blocks, 12);
+    }
+
+    #[test]
+    fn test_empty_input_round_trip() {
+        for adaptive in [false, true] {
+            let options = EncodeOptions {
+                adaptive,
+                ..EncodeOptions::default()
+            };
+            let mut encoded: Vec<u8> = Vec::new();
+            encode_stream(&b""[..], &mut encoded, &options).unwrap();
+
+            let mut decoded: Vec<u8> = Vec::new();
+            let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+            assert!(decoded.is_empty());
+            assert_eq!(summary.blocks, 0);
+            assert_eq!(summary.trailer.original_len, 0);
+        }
+    }
+
+    #[test]
+    fn test_single_symbol_round_trip() {
+        for input in [b"a".to_vec(), vec![b'z'; 10_000], vec![0u8; 3000]] {
+            for adaptive in [false, true] {
+                let options = EncodeOptions {
+                    block_size: 1024,
+                    adaptive,
+                    ..EncodeOptions::default()
+                };
+                let mut encoded: Vec<u8> = Vec::new();
+                encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+
+                let mut decoded: Vec<u8> = Vec::new();
+                decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+                assert_eq!(decoded, input, "{} bytes, adaptive {adaptive}", input.len());
+            }
+        }
+
+        // one bit per byte with a static table
+        let encoded = encode_blocks(&[b'z'; 8000], 8000);
+        assert!(encoded.len() >= 1000 && encoded.len() < 1100);
+    }
+
+    // Encodes in memory with the given block size
+    fn encode_blocks(input: &[u8], block_size: usize) -> Vec<u8> {
+        let mut encoded: Vec<u8> = Vec::new();
+        let options = EncodeOptions {
+            block_size,
+            ..EncodeOptions::default()
+        };
+        encode_stream(input, &mut encoded, &options).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn test_blocks_get_their_own_tables() {
+        // two halves with nothing in common
+        let mut input: Vec<u8> = b"abababbbaaab".repeat(400);
+        input.extend(b"0123456789".repeat(480));
+
+        let per_block = encode_blocks(&input, 4800);
+        let single = encode_blocks(&input, EncodeOptions::default().block_size);
+        assert!(per_block.len() < single.len());
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(per_block.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.blocks, 2);
+        assert_eq!(summary.tables, 2);
+    }
+
+    #[test]
+    fn test_similar_blocks_reuse_the_table() {
+        let input: Vec<u8> = b"the same text in every block. ".repeat(1000);
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encode_blocks(&input, 3000).as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.blocks, 10);
+        assert_eq!(summary.tables, 1);
+        assert_eq!(summary.trailer.original_len, input.len() as u64);
+    }
+
+    #[test]
+    fn test_adaptive_round_trip() {
+        let text = read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let mut input = text[..200_000].to_vec();
+        input.extend(
+            read(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/sample.bin"
+            ))
+            .unwrap(),
+        );
+
+        let options = EncodeOptions {
+            block_size: 64 * 1024,
+            adaptive: true,
+            ..EncodeOptions::default()
+        };
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+
+        // close to the static codes, without any table
+        let static_size = encode_blocks(&input, 64 * 1024).len();
+        assert!(encoded.len() < static_size * 105 / 100);
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.tables, 0);
+        assert_eq!(summary.adaptive_blocks, 4);
+    }
+
+    #[test]
+    fn test_context_blocks_only_where_they_pay_off() {
+        // text gains from order-1 tables, the gzip fixture does not
+        let text = read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let mut input = text[..64 * 1024].to_vec();
+        input.extend(
+            read(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/sample.bin"
+            ))
+            .unwrap(),
+        );
+
+        let options = EncodeOptions {
+            block_size: 64 * 1024,
+            context: true,
+            ..EncodeOptions::default()
+        };
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.blocks, 2);
+        assert_eq!(summary.context_blocks, 1);
+        assert_eq!(summary.tables, 1);
+    }
+
+    #[test]
+    fn test_lz77_blocks_shrink_repeated_lines() {
+        // log lines repeat most of the previous ones, which byte counts cannot see
+        let input: Vec<u8> = (0..5000)
+            .flat_map(|i| {
+                format!(
+                    "2024-05-01T12:{:02}:{:02} INFO request handled path=/api/items/{} status=200\n",
+                    i / 60 % 60,
+                    i % 60,
+                    i % 17
+                )
+                .into_bytes()
+            })
+            .collect();
+
+        let encode_level = |level: u8| {
+            let options = EncodeOptions {
+                block_size: 64 * 1024,
+                level,
+                ..EncodeOptions::default()
+            };
+            let mut encoded: Vec<u8> = Vec::new();
+            encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+            encoded
+        };
+        let huffman_only = encode_level(0);
+        let encoded = encode_level(6);
+        assert!(encoded.len() * 5 < huffman_only.len());
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.lz_blocks, summary.blocks);
+    }
+
+    #[test]
+    fn test_ans_beats_huffman_on_skewed_data() {
+        // mostly spaces, where a Huffman code cannot go below a bit per byte
+        let input: Vec<u8> = (0..200_000u32)
+            .map(|i| match i % 97 {
+                0 => b'\n',
+                13 => b'.',
+                _ => b' ',
+            })
+            .collect();
+
+        let encode_with = |coder: Coder| {
+            let options = EncodeOptions {
+                block_size: 64 * 1024,
+                coder,
+                ..EncodeOptions::default()
+            };
+            let mut encoded: Vec<u8> = Vec::new();
+            encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+            encoded
+        };
+        let huffman = encode_with(Coder::Huffman);
+        let encoded = encode_with(Coder::Ans);
+        assert!(encoded.len() * 3 < huffman.len());
+
+        // the coder comes from the header
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+        assert_eq!(summary.header.coder, Coder::Ans);
+        assert_eq!(summary.tables, 1);
+    }
+
+    #[test]
+    fn test_rejects_match_before_the_block() {
+        // a length symbol then a distance of 1 with nothing decoded yet
+        let lz = LzLengths {
+            literals: HashMap::from([(b'a' as u16, 1), (257, 1)]),
+            distances: HashMap::from([(0, 1)]),
+        };
+        let err = decode_lz_block(&lz, &[0b1000_0000], 3, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("match outside of the block"));
+
+        // 'a' then a 3 byte match, one byte more than the block holds
+        let err = decode_lz_block(&lz, &[0b0100_0000], 3, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("match outside of the block"));
+    }
+
+    #[test]
+    fn test_rejects_block_larger_than_block_size() {
+        let mut encoded = encode_blocks(b"some bytes", 64);
+        // block size in the header is right after the magic and version
+        encoded[5..9].copy_from_slice(&4u32.to_le_bytes());
+
+        let err = decode_stream(encoded.as_slice(), io::sink()).unwrap_err();
+        assert!(err.to_string().contains("larger than the block size"));
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let input = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt"));
+        let original = read(&input).expect("Unable to read test.txt");
+
+        assert_eq!(round_trip("text_round_trip", &input), original);
+    }
+
+    #[test]
+    fn test_refuses_to_overwrite_without_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.txt");
+        let encoded = dir.path().join("input.huff");
+        let decoded = dir.path().join("output.txt");
+        std::fs::write(&input, b"decoded twice into the same file\n").unwrap();
+        encode_and_output_file(&encoded, &input, &EncodeOptions::default(), false).unwrap();
+
+        decode_and_output_file(&decoded, &encoded, None, false).unwrap();
+        let err = decode_and_output_file(&decoded, &encoded, None, false).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+
+        // --force replaces the file instead of appending to it
+        decode_and_output_file(&decoded, &encoded, None, true).unwrap();
+        assert_eq!(read(&decoded).unwrap(), read(&input).unwrap());
+    }
 
-    // Get the data
-    let codes_map: HashMap<BitVec<u8, Msb0>, char> = decoded_struct.codes.into_iter().map(|(c, s)| {
-        let mut bv = BitVec::<u8, Msb0>::new();
-        for c in s.chars() {
-            bv.push(c == '1');
+    // Encodes test.txt, lets `corrupt` change the archive and returns the decode error
+    fn decode_corrupted(name: &str, corrupt: impl Fn(&mut Vec<u8>)) -> CmError {
+        let dir = std::env::temp_dir();
+        let input = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt"));
+        let encoded = dir.join(format!("cm_tool_{name}.huff"));
+        let decoded = dir.join(format!("cm_tool_{name}.out"));
+        let _ = remove_file(&decoded);
+
+        encode_and_output_file(&encoded, &input, &EncodeOptions::default(), true).unwrap();
+        let mut bytes = read(&encoded).unwrap();
+        corrupt(&mut bytes);
+        std::fs::write(&encoded, bytes).unwrap();
+
+        let err = decode_and_output_file(&decoded, &encoded, None, false).unwrap_err();
+        // nothing is left behind when decoding fails
+        assert!(!decoded.exists());
+        let _ = remove_file(&encoded);
+        let _ = remove_file(&decoded);
+        err
+    }
+
+    #[test]
+    fn test_detects_flipped_bit() {
+        let err = decode_corrupted("flipped_bit", |bytes| {
+            let middle = bytes.len() / 2;
+            bytes[middle] ^= 0x10;
+        });
+        assert!(matches!(err, CmError::Format(_) | CmError::Checksum { .. }));
+        assert!(err.to_string().contains("archive is corrupt"));
+    }
+
+    #[test]
+    fn test_gzip_magic_arriving_byte_by_byte() {
+        // A pipe handing out one byte per read
+        struct OneByte<'a>(&'a [u8]);
+        impl Read for OneByte<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
         }
-        (bv, c)
-    }).collect();
 
-    let total_bits = decoded_struct.total_bits;
-    let bits = decoded_struct.data.view_bits::<Msb0>();
+        let mut reader = OneByte(&[0x1f, 0x8b, 8, 0]);
+        assert_eq!(read_magic(&mut reader).unwrap(), gzip::MAGIC);
+        assert_eq!(reader.0, [8, 0]);
+        assert_eq!(read_magic(&mut OneByte(&[0x1f])).unwrap(), [0x1f]);
+    }
+
+    #[test]
+    fn test_reads_version_4_archives() {
+        // a version 5 archive without its seek index is a version 4 one
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(&b"abracadabra"[..], &mut encoded, &EncodeOptions::default()).unwrap();
+        let footer = encoded.len() - 16;
+        let index_offset = u64::from_le_bytes(encoded[footer..footer + 8].try_into().unwrap());
+        encoded.truncate(index_offset as usize);
+        encoded[4] = 4;
+
+        let mut decoded: Vec<u8> = Vec::new();
+        let summary = decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, b"abracadabra");
+        assert_eq!(summary.header.version, 4);
+        assert!(!summary.header.seek_index());
+    }
+
+    #[test]
+    fn test_detects_wrong_checksum() {
+        let err = decode_corrupted("wrong_checksum", |bytes| {
+            // the checksum ends the trailer, right before the seek index
+            let footer = bytes.len() - 16;
+            let index_offset = u64::from_le_bytes(bytes[footer..footer + 8].try_into().unwrap());
+            bytes[index_offset as usize - 1] ^= 0x01;
+        });
+        assert!(matches!(err, CmError::Checksum { .. }));
+        assert_eq!(err.exit_code(), 6);
+    }
+
+    #[test]
+    fn test_detects_truncated_file() {
+        let err = decode_corrupted("truncated", |bytes| {
+            bytes.truncate(bytes.len() - 100);
+        });
+        assert_eq!(err.to_string(), "archive is truncated");
+    }
 
-    // Loop to get the main message
-    let mut decoded_text = String::new();
-    let mut candidate = BitVec::<u8, Msb0>::new();
+    // The decoder this table replaced: one bit at a time, hashing the
+    // candidate code after every bit. Kept to benchmark against.
+    fn decode_per_bit(encoded: &[u8]) -> Vec<u8> {
+        let mut reader = encoded;
+        let header = Header::read(&mut reader).unwrap();
 
-    for i in 0..total_bits {
-        let bit = bits[i];
-        candidate.push(bit);
+        let mut codes_map: HashMap<(u64, u8), u8> = HashMap::new();
+        let mut decoded = Vec::new();
+        loop {
+            let block = BlockHeader::read(&mut reader, header.coder).unwrap();
+            if block.kind == BlockKind::End {
+                break;
+            }
+            if let Some(lengths) = &block.lengths {
+                codes_map = canonical_codes(lengths)
+                    .into_iter()
+                    .map(|(byte, s)| {
+                        let bits = s
+                            .chars()
+                            .fold(0u64, |acc, c| (acc << 1) | (c == '1') as u64);
+                        ((bits, s.len() as u8), byte)
+                    })
+                    .collect();
+            }
 
-        if let Some(&ch) = codes_map.get(&candidate) {
-            decoded_text.push(ch);
-            candidate.clear();
+            let (payload, rest) = reader.split_at(block.payload_len as usize);
+            reader = rest;
+            let mut bits = BitReader::new(payload);
+            let mut candidate: (u64, u8) = (0, 0);
+            let mut remaining = block.raw_len;
+            while remaining > 0 {
+                let bit = bits.read_bit().unwrap();
+                candidate = ((candidate.0 << 1) | bit as u64, candidate.1 + 1);
+                if let Some(&byte) = codes_map.get(&candidate) {
+                    decoded.push(byte);
+                    candidate = (0, 0);
+                    remaining -= 1;
+                }
+            }
         }
+        decoded
     }
 
-    // Write to the output file
-    let mut output_file = OpenOptions::new().create(true).write(true).append(true).open(output_path).expect("Error setting output_file");
+    fn mb_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    }
+
+    // Throughput of the table decoder against the per-bit decoder, run with
+    // cargo test --release -- --ignored --nocapture bench_decode
+    #[test]
+    #[ignore]
+    fn bench_decode() {
+        let text = read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let large: Vec<u8> = text
+            .iter()
+            .copied()
+            .cycle()
+            .take(100 * 1024 * 1024)
+            .collect();
+
+        for (name, data) in [("test.txt", text), ("100 MB", large)] {
+            let dir = std::env::temp_dir();
+            let input = dir.join("cm_tool_bench_decode.in");
+            let encoded = dir.join("cm_tool_bench_decode.huff");
+            let decoded = dir.join("cm_tool_bench_decode.out");
+            std::fs::write(&input, &data).unwrap();
+            encode_and_output_file(&encoded, &input, &EncodeOptions::default(), true).unwrap();
+            let encoded_bytes = read(&encoded).unwrap();
 
-    output_file.write_all(decoded_text.as_bytes()).expect("error writing to file");
+            let _ = remove_file(&decoded);
+            let timer = Instant::now();
+            decode_and_output_file(&decoded, &encoded, None, false).unwrap();
+            let table_time = timer.elapsed();
+            assert_eq!(read(&decoded).unwrap(), data);
+
+            let timer = Instant::now();
+            let per_bit = decode_per_bit(&encoded_bytes);
+            let per_bit_time = timer.elapsed();
+            assert_eq!(per_bit, data);
+
+            println!(
+                "{name}: table {:.1} MB/s, per-bit {:.1} MB/s ({:.1}x)",
+                mb_per_sec(data.len(), table_time),
+                mb_per_sec(data.len(), per_bit_time),
+                per_bit_time.as_secs_f64() / table_time.as_secs_f64()
+            );
+
+            for path in [&input, &encoded, &decoded] {
+                let _ = remove_file(path);
+            }
+        }
+    }
 }
@@ -0,0 +1,154 @@
+// Table driven decoding of canonical Huffman codes
+//
+// The next PRIMARY_BITS bits of the stream index straight into a table that
+// holds the symbol and its code length, so most symbols cost one lookup.
+// Codes longer than the table are decoded bit by bit with the canonical
+// first code / count per length, which needs no tree or HashMap either.
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+};
+
+use crate::bit_io::BitReader;
+
+// Width of the lookup table, 2^PRIMARY_BITS entries at most
+const PRIMARY_BITS: u8 = 11;
+
+pub struct DecodeTable<T> {
+    // Width of `primary`, the longest code if it is shorter than PRIMARY_BITS
+    bits: u8,
+    // (symbol, code length), a length of 0 means the code is longer than `bits`
+    primary: Vec<(T, u8)>,
+    // Symbols sorted by (code length, symbol)
+    symbols: Vec<T>,
+    // Per code length: first code, number of codes and index of the first symbol
+    first_code: Vec<u64>,
+    count: Vec<u64>,
+    offset: Vec<usize>,
+    max_len: u8,
+}
+
+impl<T: Copy + Ord + Default> DecodeTable<T> {
+    pub fn new(lengths: &HashMap<T, u8>) -> Self {
+        let mut sorted: Vec<(u8, T)> = lengths.iter().map(|(sym, len)| (*len, *sym)).collect();
+        sorted.sort();
+
+        let max_len = sorted.last().map_or(0, |(len, _)| *len);
+        let symbols: Vec<T> = sorted.iter().map(|(_, sym)| *sym).collect();
+
+        let mut count = vec![0u64; max_len as usize + 1];
+        for (len, _) in &sorted {
+            count[*len as usize] += 1;
+        }
+
+        // Same assignment as generate_huffman_codes::canonical_codes
+        let mut first_code = vec![0u64; max_len as usize + 1];
+        let mut offset = vec![0usize; max_len as usize + 1];
+        let mut code = 0u64;
+        let mut index = 0usize;
+        for len in 1..=max_len as usize {
+            code = (code + count[len - 1]) << 1;
+            first_code[len] = code;
+            offset[len] = index;
+            index += count[len] as usize;
+        }
+
+        // Every code that fits fills all the table slots starting with it
+        let bits = max_len.min(PRIMARY_BITS);
+        let mut primary = vec![(T::default(), 0u8); 1 << bits];
+        for (i, (len, sym)) in sorted.iter().enumerate() {
+            if *len == 0 || *len > bits {
+                continue;
+            }
+            let code = first_code[*len as usize] + (i - offset[*len as usize]) as u64;
+            let start = (code << (bits - len)) as usize;
+            let end = start + (1 << (bits - len));
+            for slot in &mut primary[start..end] {
+                *slot = (*sym, *len);
+            }
+        }
+
+        DecodeTable {
+            bits,
+            primary,
+            symbols,
+            first_code,
+            count,
+            offset,
+            max_len,
+        }
+    }
+
+    // Decodes one symbol and returns it with the length of its code
+    pub fn decode<R: Read>(&self, reader: &mut BitReader<R>) -> io::Result<(T, u8)> {
+        let index = reader.peek_bits(self.bits)? as usize;
+        let (sym, len) = self.primary[index];
+        if len > 0 {
+            reader.consume(len)?;
+            return Ok((sym, len));
+        }
+
+        // Long code, walk the lengths one bit at a time
+        let mut code = 0u64;
+        for len in 1..=self.max_len as usize {
+            code = (code << 1) | reader.read_bit()? as u64;
+            let first = self.first_code[len];
+            if code >= first && code - first < self.count[len] {
+                let sym = self.symbols[self.offset[len] + (code - first) as usize];
+                return Ok((sym, len as u8));
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "encoded data does not match the code table",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bit_io::BitWriter, generate_huffman_codes::canonical_codes, helpers::pack_codes};
+
+    fn encode(lengths: &HashMap<u8, u8>, input: &[u8]) -> Vec<u8> {
+        let codes = pack_codes(&canonical_codes(lengths));
+        let mut writer = BitWriter::new(Vec::new());
+        for byte in input {
+            let (bits, len) = codes[*byte as usize];
+            writer.write_bits(bits, len).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode_short_codes() {
+        let lengths = HashMap::from([(b'a', 1u8), (b'b', 2), (b'c', 3), (b'd', 3)]);
+        let input = b"abacabadcd";
+        let encoded = encode(&lengths, input);
+
+        let table = DecodeTable::new(&lengths);
+        let mut reader = BitReader::new(encoded.as_slice());
+        let decoded: Vec<u8> = (0..input.len())
+            .map(|_| table.decode(&mut reader).unwrap().0)
+            .collect();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_decode_codes_longer_than_table() {
+        // lengths 1, 2, ..., 19, 19 form a complete code much longer than PRIMARY_BITS
+        let mut lengths: HashMap<u8, u8> = (0..19u8).map(|i| (i, i + 1)).collect();
+        lengths.insert(19, 19);
+        let input: Vec<u8> = (0..20u8).chain((0..20u8).rev()).collect();
+        let encoded = encode(&lengths, &input);
+
+        let table = DecodeTable::new(&lengths);
+        let mut reader = BitReader::new(encoded.as_slice());
+        for expected in &input {
+            let (sym, len) = table.decode(&mut reader).unwrap();
+            assert_eq!(sym, *expected);
+            assert_eq!(len, lengths[expected]);
+        }
+    }
+}
@@ -0,0 +1,614 @@
+// DEFLATE (RFC 1951) blocks, written and read
+//
+// A block is stored as is, or coded with the fixed Huffman tables of the
+// RFC, or with dynamic tables built from its own symbol counts with the
+// generator used for .huff blocks. The encoder writes whichever of the
+// three takes the fewest bits. Matches come from the LZ77 stage and do not
+// reach into the previous block.
+//
+// Huffman codes are canonical, as in .huff files, but are packed starting
+// from their most significant bit into a stream that is otherwise least
+// significant bit first, so they are bit reversed for LsbBitWriter.
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use crc32fast::Hasher;
+
+use crate::{
+    bit_io::{LsbBitReader, LsbBitWriter},
+    error::CmError,
+    generate_huffman_codes::{canonical_codes, code_lengths, length_limited_code_lengths},
+    helpers,
+    huffman::huffman_tree,
+    lz77::{self, Token, WINDOW_SIZE},
+};
+
+const END_OF_BLOCK: u16 = 256;
+// Longest code DEFLATE allows, and for the code length code
+const MAX_CODE_LEN: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LEN: u8 = 7;
+// Order the code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+// Most bytes in a stored block
+const MAX_STORED: usize = 65535;
+
+// Code lengths of the fixed literal/length table, RFC 1951 section 3.2.6
+fn fixed_literal_lengths() -> HashMap<u16, u8> {
+    (0..288u16)
+        .map(|symbol| {
+            let len = match symbol {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            };
+            (symbol, len)
+        })
+        .collect()
+}
+
+// Every distance code of the fixed tables is 5 bits long
+fn fixed_distance_lengths() -> HashMap<u16, u8> {
+    (0..32u16).map(|symbol| (symbol, 5)).collect()
+}
+
+// Code lengths of at most `max_len` bits for the counted symbols. Like zlib,
+// at least two symbols get a code, some inflaters refuse a lone 1 bit code.
+fn limited_code_lengths(counts: &HashMap<u16, u64>, max_len: u8) -> HashMap<u16, u8> {
+    let mut counts = counts.clone();
+    let mut unused = 0u16;
+    while counts.len() < 2 {
+        counts.entry(unused).or_insert(0);
+        unused += 1;
+    }
+
+    let lengths = huffman_tree(counts.clone())
+        .map(code_lengths)
+        .unwrap_or_default();
+    if lengths.values().any(|len| *len > max_len) {
+        return length_limited_code_lengths(&counts, max_len)
+            .expect("DEFLATE alphabets fit in codes of the longest length");
+    }
+    lengths
+}
+
+// (code, length) indexed by symbol, the code bit reversed for LsbBitWriter
+fn writer_codes(lengths: &HashMap<u16, u8>) -> Vec<(u64, u8)> {
+    helpers::pack_codes(&canonical_codes(lengths))
+        .into_iter()
+        .map(|(bits, len)| {
+            if len == 0 {
+                (0, 0)
+            } else {
+                (bits.reverse_bits() >> (64 - len), len)
+            }
+        })
+        .collect()
+}
+
+// Code lengths as a dense list, zeros for the symbols without a code
+fn dense_lengths(lengths: &HashMap<u16, u8>, min_count: usize) -> Vec<u8> {
+    let count = lengths
+        .keys()
+        .map(|symbol| *symbol as usize + 1)
+        .max()
+        .unwrap_or(0)
+        .max(min_count);
+    let mut dense = vec![0u8; count];
+    for (symbol, len) in lengths {
+        dense[*symbol as usize] = *len;
+    }
+    dense
+}
+
+// Run length codes for a list of code lengths: 0-15 are lengths, 16 repeats
+// the previous length 3-6 times, 17 and 18 give 3-10 and 11-138 zeros.
+// Each item is (symbol, value of its extra bits).
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut items: Vec<(u8, u8)> = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..].iter().take_while(|l| **l == len).count();
+        if len == 0 && run >= 11 {
+            let run = run.min(138);
+            items.push((18, (run - 11) as u8));
+            i += run;
+        } else if len == 0 && run >= 3 {
+            items.push((17, (run - 3) as u8));
+            i += run;
+        } else if len != 0 && run >= 4 {
+            items.push((len, 0));
+            let repeat = (run - 1).min(6);
+            items.push((16, (repeat - 3) as u8));
+            i += 1 + repeat;
+        } else {
+            items.push((len, 0));
+            i += 1;
+        }
+    }
+    items
+}
+
+fn extra_bits(code_length_symbol: u8) -> u8 {
+    match code_length_symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+// Tables of a dynamic block and how they are sent
+struct DynamicTables {
+    literals: HashMap<u16, u8>,
+    distances: HashMap<u16, u8>,
+    literal_count: usize,
+    distance_count: usize,
+    items: Vec<(u8, u8)>,
+    code_length_lengths: HashMap<u16, u8>,
+    // Number of code length code lengths sent, in CODE_LENGTH_ORDER
+    code_length_count: usize,
+}
+
+impl DynamicTables {
+    fn new(literal_counts: &HashMap<u16, u64>, distance_counts: &HashMap<u16, u64>) -> Self {
+        let literals = limited_code_lengths(literal_counts, MAX_CODE_LEN);
+        let distances = limited_code_lengths(distance_counts, MAX_CODE_LEN);
+        let mut all = dense_lengths(&literals, 257);
+        let literal_count = all.len();
+        let dense_distances = dense_lengths(&distances, 1);
+        let distance_count = dense_distances.len();
+        all.extend(dense_distances);
+
+        // The run length symbols are Huffman coded too
+        let items = run_lengths(&all);
+        let mut item_counts: HashMap<u16, u64> = HashMap::new();
+        for (symbol, _) in &items {
+            *item_counts.entry(*symbol as u16).or_default() += 1;
+        }
+        let code_length_lengths = limited_code_lengths(&item_counts, MAX_CODE_LENGTH_CODE_LEN);
+        let code_length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|symbol| code_length_lengths.contains_key(&(*symbol as u16)))
+            .map_or(4, |last| (last + 1).max(4));
+
+        DynamicTables {
+            literals,
+            distances,
+            literal_count,
+            distance_count,
+            items,
+            code_length_lengths,
+            code_length_count,
+        }
+    }
+
+    // Bits of the tables once written
+    fn cost(&self) -> u64 {
+        let items: u64 = self
+            .items
+            .iter()
+            .map(|(symbol, _)| {
+                (self.code_length_lengths[&(*symbol as u16)] + extra_bits(*symbol)) as u64
+            })
+            .sum();
+        5 + 5 + 4 + 3 * self.code_length_count as u64 + items
+    }
+
+    fn write<W: Write>(&self, writer: &mut LsbBitWriter<W>) -> io::Result<()> {
+        writer.write_bits((self.literal_count - 257) as u64, 5)?;
+        writer.write_bits((self.distance_count - 1) as u64, 5)?;
+        writer.write_bits((self.code_length_count - 4) as u64, 4)?;
+        for symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            let len = self
+                .code_length_lengths
+                .get(&(*symbol as u16))
+                .copied()
+                .unwrap_or(0);
+            writer.write_bits(len as u64, 3)?;
+        }
+
+        let codes = writer_codes(&self.code_length_lengths);
+        for (symbol, extra) in &self.items {
+            let (bits, len) = codes[*symbol as usize];
+            writer.write_bits(bits, len)?;
+            writer.write_bits(*extra as u64, extra_bits(*symbol))?;
+        }
+        Ok(())
+    }
+}
+
+// Symbol counts of a block's tokens, with the end of block symbol
+fn count_symbols(tokens: &[Token]) -> (HashMap<u16, u64>, HashMap<u16, u64>) {
+    let mut literal_counts: HashMap<u16, u64> = HashMap::from([(END_OF_BLOCK, 1)]);
+    let mut distance_counts: HashMap<u16, u64> = HashMap::new();
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => *literal_counts.entry(byte as u16).or_default() += 1,
+            Token::Match { length, distance } => {
+                *literal_counts
+                    .entry(lz77::length_symbol(length).0)
+                    .or_default() += 1;
+                *distance_counts
+                    .entry(lz77::distance_symbol(distance).0)
+                    .or_default() += 1;
+            }
+        }
+    }
+    (literal_counts, distance_counts)
+}
+
+// Bits the tokens take with the given code lengths, extra bits included
+fn payload_cost(
+    literal_counts: &HashMap<u16, u64>,
+    distance_counts: &HashMap<u16, u64>,
+    literals: &HashMap<u16, u8>,
+    distances: &HashMap<u16, u8>,
+) -> u64 {
+    let literal_bits: u64 = literal_counts
+        .iter()
+        .map(|(symbol, count)| {
+            let extra = lz77::length_base(*symbol).map_or(0, |(_, extra)| extra);
+            count * (literals[symbol] + extra) as u64
+        })
+        .sum();
+    let distance_bits: u64 = distance_counts
+        .iter()
+        .map(|(symbol, count)| {
+            let extra = lz77::distance_base(*symbol).map_or(0, |(_, extra)| extra);
+            count * (distances[symbol] + extra) as u64
+        })
+        .sum();
+    literal_bits + distance_bits
+}
+
+// Writes `data` as one or more DEFLATE blocks, `last` marks the final one
+pub fn write_block<W: Write>(
+    writer: &mut LsbBitWriter<W>,
+    data: &[u8],
+    level: u8,
+    last: bool,
+) -> io::Result<()> {
+    let tokens: Vec<Token> = if level > 0 {
+        lz77::find_matches(data, level)
+    } else {
+        data.iter().map(|byte| Token::Literal(*byte)).collect()
+    };
+    let (literal_counts, distance_counts) = count_symbols(&tokens);
+
+    let fixed_literals = fixed_literal_lengths();
+    let fixed_distances = fixed_distance_lengths();
+    let fixed_cost = 3 + payload_cost(
+        &literal_counts,
+        &distance_counts,
+        &fixed_literals,
+        &fixed_distances,
+    );
+    let dynamic = DynamicTables::new(&literal_counts, &distance_counts);
+    let dynamic_cost = 3
+        + dynamic.cost()
+        + payload_cost(
+            &literal_counts,
+            &distance_counts,
+            &dynamic.literals,
+            &dynamic.distances,
+        );
+    // header, padding to a byte and the lengths of every stored block
+    let stored_cost =
+        data.len().div_ceil(MAX_STORED).max(1) as u64 * (3 + 7 + 32) + data.len() as u64 * 8;
+
+    if stored_cost < fixed_cost.min(dynamic_cost) {
+        return write_stored(writer, data, last);
+    }
+
+    writer.write_bits(last as u64, 1)?;
+    if fixed_cost <= dynamic_cost {
+        writer.write_bits(1, 2)?;
+        write_tokens(writer, &tokens, &fixed_literals, &fixed_distances)
+    } else {
+        writer.write_bits(2, 2)?;
+        dynamic.write(writer)?;
+        write_tokens(writer, &tokens, &dynamic.literals, &dynamic.distances)
+    }
+}
+
+fn write_stored<W: Write>(writer: &mut LsbBitWriter<W>, data: &[u8], last: bool) -> io::Result<()> {
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(MAX_STORED).collect()
+    };
+    let count = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        writer.write_bits((last && i + 1 == count) as u64, 1)?;
+        writer.write_bits(0, 2)?;
+        writer.align();
+        let len = chunk.len() as u16;
+        writer.write_bytes(&len.to_le_bytes())?;
+        writer.write_bytes(&(!len).to_le_bytes())?;
+        writer.write_bytes(chunk)?;
+    }
+    Ok(())
+}
+
+fn write_tokens<W: Write>(
+    writer: &mut LsbBitWriter<W>,
+    tokens: &[Token],
+    literals: &HashMap<u16, u8>,
+    distances: &HashMap<u16, u8>,
+) -> io::Result<()> {
+    let literals = writer_codes(literals);
+    let distances = writer_codes(distances);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                let (bits, len) = literals[byte as usize];
+                writer.write_bits(bits, len)?;
+            }
+            Token::Match { length, distance } => {
+                let (symbol, extra, extra_len) = lz77::length_symbol(length);
+                let (bits, len) = literals[symbol as usize];
+                writer.write_bits(bits, len)?;
+                writer.write_bits(extra as u64, extra_len)?;
+
+                let (symbol, extra, extra_len) = lz77::distance_symbol(distance);
+                let (bits, len) = distances[symbol as usize];
+                writer.write_bits(bits, len)?;
+                writer.write_bits(extra as u64, extra_len)?;
+            }
+        }
+    }
+    let (bits, len) = literals[END_OF_BLOCK as usize];
+    writer.write_bits(bits, len)
+}
+
+// Decoding table indexed by the next `bits` bits of the stream, which hold
+// a reversed code in their low bits: every slot starting with a code holds
+// its (symbol, length), a length of 0 is not a code
+struct Huffman {
+    bits: u8,
+    table: Vec<(u16, u8)>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, CmError> {
+        let lengths: HashMap<u16, u8> = (0..)
+            .zip(lengths)
+            .filter(|(_, len)| **len > 0)
+            .map(|(symbol, len)| (symbol, *len))
+            .collect();
+
+        // More codes of a length than there is room for cannot be decoded
+        let kraft: u64 = lengths
+            .values()
+            .map(|len| 1u64 << (MAX_CODE_LEN - len))
+            .sum();
+        if kraft > 1 << MAX_CODE_LEN {
+            return Err(CmError::format(
+                "deflate stream is corrupt: invalid code lengths",
+            ));
+        }
+
+        let bits = lengths.values().copied().max().unwrap_or(0);
+        let mut table = vec![(0u16, 0u8); 1 << bits];
+        for (symbol, (code, len)) in writer_codes(&lengths).into_iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            for slot in table.iter_mut().skip(code as usize).step_by(1 << len) {
+                *slot = (symbol as u16, len);
+            }
+        }
+        Ok(Huffman { bits, table })
+    }
+
+    fn decode<R: Read>(&self, reader: &mut LsbBitReader<R>) -> Result<u16, CmError> {
+        let index = reader.peek_bits(self.bits).map_err(CmError::truncated)?;
+        let (symbol, len) = self.table[index as usize];
+        if len == 0 {
+            return Err(CmError::format(
+                "deflate stream is corrupt: invalid Huffman code",
+            ));
+        }
+        reader.consume(len).map_err(CmError::truncated)?;
+        Ok(symbol)
+    }
+}
+
+fn read_bits<R: Read>(reader: &mut LsbBitReader<R>, n: u8) -> Result<usize, CmError> {
+    Ok(reader.read_bits(n).map_err(CmError::truncated)? as usize)
+}
+
+// Reads the tables at the start of a dynamic block
+fn read_dynamic_tables<R: Read>(
+    reader: &mut LsbBitReader<R>,
+) -> Result<(Huffman, Huffman), CmError> {
+    let literal_count = read_bits(reader, 5)? + 257;
+    let distance_count = read_bits(reader, 5)? + 1;
+    let code_length_count = read_bits(reader, 4)? + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(CmError::format("deflate stream is corrupt: too many codes"));
+    }
+
+    let mut code_length_lengths = [0u8; 19];
+    for symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[*symbol] = read_bits(reader, 3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths)?;
+
+    let total = literal_count + distance_count;
+    let mut lengths: Vec<u8> = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (len, repeat) = match code_length_code.decode(reader)? {
+            16 => {
+                let Some(previous) = lengths.last() else {
+                    return Err(CmError::format(
+                        "deflate stream is corrupt: repeat with no length before it",
+                    ));
+                };
+                (*previous, 3 + read_bits(reader, 2)?)
+            }
+            17 => (0, 3 + read_bits(reader, 3)?),
+            18 => (0, 11 + read_bits(reader, 7)?),
+            len => (len as u8, 1),
+        };
+        if lengths.len() + repeat > total {
+            return Err(CmError::format(
+                "deflate stream is corrupt: too many code lengths",
+            ));
+        }
+        lengths.extend(std::iter::repeat_n(len, repeat));
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(CmError::format(
+            "deflate stream is corrupt: no end of block code",
+        ));
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+// Decoded bytes not written out yet, the last WINDOW_SIZE are kept for
+// the matches to copy from
+struct Window<'a, W: Write> {
+    bytes: Vec<u8>,
+    writer: &'a mut W,
+    hasher: &'a mut Hasher,
+    written: u64,
+}
+
+impl<W: Write> Window<'_, W> {
+    // Copies `length` bytes from `distance` back, the copy may overlap itself
+    fn copy(&mut self, distance: usize, length: usize) -> Result<(), CmError> {
+        if distance > self.bytes.len() {
+            return Err(CmError::format(
+                "deflate stream is corrupt: match reaches before the start",
+            ));
+        }
+        let start = self.bytes.len() - distance;
+        for i in start..start + length {
+            self.bytes.push(self.bytes[i]);
+        }
+        Ok(())
+    }
+
+    // Writes out what the matches can no longer reach, or everything
+    fn flush(&mut self, all: bool) -> io::Result<()> {
+        let keep = if all { 0 } else { WINDOW_SIZE };
+        if !all && self.bytes.len() < 4 * WINDOW_SIZE {
+            return Ok(());
+        }
+        let out = self.bytes.len() - keep;
+        self.writer.write_all(&self.bytes[..out])?;
+        self.hasher.update(&self.bytes[..out]);
+        self.written += out as u64;
+        self.bytes.drain(..out);
+        Ok(())
+    }
+}
+
+// Decodes the literals and matches of a fixed or dynamic block
+fn inflate_codes<R: Read, W: Write>(
+    reader: &mut LsbBitReader<R>,
+    literals: &Huffman,
+    distances: &Huffman,
+    window: &mut Window<W>,
+) -> Result<(), CmError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if let Ok(byte) = u8::try_from(symbol) {
+            // A block of literals only is as long as it likes, flush as it goes
+            window.bytes.push(byte);
+            window.flush(false)?;
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        let Some((base, extra_len)) = lz77::length_base(symbol) else {
+            return Err(CmError::format(format!(
+                "deflate stream is corrupt: invalid length symbol {symbol}"
+            )));
+        };
+        let length = base as usize + read_bits(reader, extra_len)?;
+
+        let symbol = distances.decode(reader)?;
+        let Some((base, extra_len)) = lz77::distance_base(symbol) else {
+            return Err(CmError::format(format!(
+                "deflate stream is corrupt: invalid distance symbol {symbol}"
+            )));
+        };
+        let distance = base as usize + read_bits(reader, extra_len)?;
+        window.copy(distance, length)?;
+        window.flush(false)?;
+    }
+}
+
+// Decompresses one DEFLATE stream from `reader` into `writer`, stopping after
+// its last block. Returns the number of bytes written, which also go into `hasher`.
+pub fn inflate<R: Read, W: Write>(
+    reader: &mut LsbBitReader<R>,
+    writer: &mut W,
+    hasher: &mut Hasher,
+) -> Result<u64, CmError> {
+    let mut window = Window {
+        bytes: Vec::new(),
+        writer,
+        hasher,
+        written: 0,
+    };
+    let mut fixed: Option<(Huffman, Huffman)> = None;
+
+    loop {
+        let last = read_bits(reader, 1)? == 1;
+        match read_bits(reader, 2)? {
+            0 => {
+                reader.align();
+                let len = read_bits(reader, 16)?;
+                let inverse = read_bits(reader, 16)?;
+                if len != !inverse & 0xffff {
+                    return Err(CmError::format(
+                        "deflate stream is corrupt: stored block length mismatch",
+                    ));
+                }
+                for _ in 0..len {
+                    window.bytes.push(read_bits(reader, 8)? as u8);
+                }
+            }
+            1 => {
+                if fixed.is_none() {
+                    fixed = Some((
+                        Huffman::new(&dense_lengths(&fixed_literal_lengths(), 0))?,
+                        Huffman::new(&dense_lengths(&fixed_distance_lengths(), 0))?,
+                    ));
+                }
+                let (literals, distances) = fixed.as_ref().expect("built above");
+                inflate_codes(reader, literals, distances, &mut window)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_tables(reader)?;
+                inflate_codes(reader, &literals, &distances, &mut window)?;
+            }
+            _ => {
+                return Err(CmError::format(
+                    "deflate stream is corrupt: invalid block type",
+                ));
+            }
+        }
+        window.flush(false)?;
+        if last {
+            break;
+        }
+    }
+
+    window.flush(true)?;
+    Ok(window.written)
+}
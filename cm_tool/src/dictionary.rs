@@ -0,0 +1,288 @@
+// Dictionaries: a code table and a rANS frequency table trained on sample
+// files, shared by every archive encoded with them. Blocks coded with the
+// dictionary only store its ID, not a table, which is most of an archive
+// when the inputs are small.
+//
+// .huffdict file
+//   magic        4 bytes  "CMHD"
+//   version      1 byte
+//   id           4 bytes  CRC32 of the tables below, little endian
+//   lengths      256 bytes  code length of every byte
+//   frequencies  512 bytes  rANS share of every byte, little endian, adding up to 2^15
+//
+// Every byte gets a code and a share, even the ones the samples never had,
+// so any input can be encoded with the dictionary.
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use crc32fast::Hasher;
+
+use crate::{
+    ans::Frequencies, archive, error::CmError, generate_huffman_codes::length_limited_code_lengths,
+    header::check_code_lengths, helpers,
+};
+
+pub const MAGIC: [u8; 4] = *b"CMHD";
+pub const VERSION: u8 = 1;
+
+// Unseen bytes get a count of 1, keep their codes within a decodable length
+const MAX_CODE_LEN: u8 = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    pub id: u32,
+    pub lengths: HashMap<u8, u8>,
+    pub frequencies: HashMap<u8, u16>,
+}
+
+impl Dictionary {
+    // Builds the tables from the byte counts of the samples
+    pub fn from_counts(char_map: &HashMap<u8, u64>) -> Self {
+        let counts: HashMap<u8, u64> = (0..=255u8)
+            .map(|byte| (byte, char_map.get(&byte).copied().unwrap_or(0).max(1)))
+            .collect();
+        let lengths = length_limited_code_lengths(&counts, MAX_CODE_LEN)
+            .expect("256 bytes fit in 32 bit codes");
+        let frequencies = Frequencies::new(&counts).table();
+        Self::with_tables(lengths, frequencies)
+    }
+
+    fn with_tables(lengths: HashMap<u8, u8>, frequencies: HashMap<u8, u16>) -> Self {
+        let mut dictionary = Dictionary {
+            id: 0,
+            lengths,
+            frequencies,
+        };
+        dictionary.id = crc32(&dictionary.tables());
+        dictionary
+    }
+
+    // The tables as the file stores them
+    fn tables(&self) -> Vec<u8> {
+        let mut tables: Vec<u8> = (0..=255u8).map(|byte| self.lengths[&byte]).collect();
+        for byte in 0..=255u8 {
+            tables.extend_from_slice(&self.frequencies[&byte].to_le_bytes());
+        }
+        tables
+    }
+
+    pub fn write<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&self.id.to_le_bytes())?;
+        writer.write_all(&self.tables())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Dictionary, CmError> {
+        let mut buf = [0u8; 4 + 1 + 4 + 256 + 512];
+        reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => CmError::format("not a cm_tool dictionary"),
+            _ => CmError::from(e),
+        })?;
+        if buf[..4] != MAGIC {
+            return Err(CmError::format("not a cm_tool dictionary"));
+        }
+        if buf[4] != VERSION {
+            return Err(CmError::UnsupportedVersion(buf[4]));
+        }
+
+        let id = u32::from_le_bytes(buf[5..9].try_into().expect("4 bytes"));
+        let tables = &buf[9..];
+        if crc32(tables) != id {
+            return Err(CmError::format(
+                "dictionary is corrupt: tables do not match its ID",
+            ));
+        }
+        let lengths: HashMap<u8, u8> = (0..=255u8)
+            .map(|byte| (byte, tables[byte as usize]))
+            .collect();
+        let frequencies: HashMap<u8, u16> = (0..=255u8)
+            .map(|byte| {
+                let at = 256 + 2 * byte as usize;
+                (byte, u16::from_le_bytes([tables[at], tables[at + 1]]))
+            })
+            .collect();
+        // The decoders only take tables that make sense
+        if check_code_lengths(&lengths).is_err() {
+            return Err(CmError::format("dictionary is corrupt: bad code lengths"));
+        }
+        Frequencies::from_table(&frequencies)?;
+        Ok(Dictionary {
+            id,
+            lengths,
+            frequencies,
+        })
+    }
+
+    pub fn load(path: &PathBuf) -> Result<Dictionary, CmError> {
+        let file = File::open(path).map_err(|e| helpers::with_path(e, path))?;
+        Self::read(&mut BufReader::new(file))
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+// Trains a dictionary on every file below `input_path` (or on that file)
+// and writes it to `output_path`. Returns it with the number of samples.
+pub fn train(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    force: bool,
+) -> Result<(Dictionary, u64), CmError> {
+    let metadata = fs::metadata(input_path).map_err(|e| helpers::with_path(e, input_path))?;
+    let mut files: Vec<(PathBuf, String, Metadata)> = Vec::new();
+    if metadata.is_dir() {
+        archive::walk(input_path, "", None, &mut files)?;
+    } else {
+        files.push((input_path.clone(), String::new(), metadata));
+    }
+
+    let mut char_map: HashMap<u8, u64> = HashMap::new();
+    let mut samples = 0u64;
+    for (file_path, _, metadata) in &files {
+        if metadata.is_dir() {
+            continue;
+        }
+        let file = File::open(file_path).map_err(|e| helpers::with_path(e, file_path))?;
+        for (byte, count) in helpers::get_char_occurence(BufReader::new(file))? {
+            *char_map.entry(byte).or_default() += count;
+        }
+        samples += 1;
+    }
+    if samples == 0 {
+        return Err(CmError::InvalidOptions(format!(
+            "no sample files in {}",
+            input_path.display()
+        )));
+    }
+
+    let dictionary = Dictionary::from_counts(&char_map);
+    helpers::write_output(output_path, force, |writer| {
+        dictionary.write(writer)?;
+        Ok(())
+    })?;
+    Ok((dictionary, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_round_trip() {
+        let char_map = helpers::get_char_occurence(&br#"{"id": 1, "name": "a"}"#[..]).unwrap();
+        let dictionary = Dictionary::from_counts(&char_map);
+        // bytes the samples never had still get a code
+        assert_eq!(dictionary.lengths.len(), 256);
+        assert!(dictionary.lengths[&b'"'] < dictionary.lengths[&b'~']);
+
+        let mut buf: Vec<u8> = Vec::new();
+        dictionary.write(&mut buf).unwrap();
+        assert_eq!(Dictionary::read(&mut buf.as_slice()).unwrap(), dictionary);
+
+        let mut corrupt = buf.clone();
+        corrupt[20] ^= 1;
+        let err = Dictionary::read(&mut corrupt.as_slice()).unwrap_err();
+        assert!(err.to_string().starts_with("dictionary is corrupt"));
+        let err = Dictionary::read(&mut &buf[..100]).unwrap_err();
+        assert_eq!(err.to_string(), "not a cm_tool dictionary");
+    }
+
+    #[test]
+    fn test_64_bit_codes() {
+        use crate::{
+            decode::decode_stream_with_dictionary,
+            encode::{EncodeOptions, encode_stream},
+        };
+        use std::sync::Arc;
+
+        // the longest codes a dictionary may have, the first one all zeros
+        let frequencies = Dictionary::from_counts(&HashMap::new()).frequencies;
+        let lengths: HashMap<u8, u8> = (0..=255u8).map(|byte| (byte, 64)).collect();
+        let dictionary = Dictionary::with_tables(lengths, frequencies);
+        let mut buf: Vec<u8> = Vec::new();
+        dictionary.write(&mut buf).unwrap();
+        let dictionary = Arc::new(Dictionary::read(&mut buf.as_slice()).unwrap());
+
+        let options = EncodeOptions {
+            dictionary: Some(dictionary.clone()),
+            ..EncodeOptions::default()
+        };
+        let input = [0u8, 0, 1, 255];
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(&input[..], &mut encoded, &options).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        decode_stream_with_dictionary(encoded.as_slice(), &mut decoded, Some(dictionary)).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_small_messages_only_store_the_id() {
+        use crate::{
+            decode::decode_stream_with_dictionary,
+            encode::{EncodeOptions, encode_stream},
+            header::Coder,
+        };
+        use std::sync::Arc;
+
+        let message = |i: u32| {
+            format!(
+                r#"{{"id": {i}, "user": "user-{}", "status": "ok", "items": [{}]}}"#,
+                i * 7,
+                i % 5
+            )
+        };
+        let mut char_map: HashMap<u8, u64> = HashMap::new();
+        for i in 0..1000 {
+            for (byte, count) in helpers::get_char_occurence(message(i).as_bytes()).unwrap() {
+                *char_map.entry(byte).or_default() += count;
+            }
+        }
+        let dictionary = Arc::new(Dictionary::from_counts(&char_map));
+
+        let input = message(123_456);
+        for coder in [Coder::Huffman, Coder::Ans] {
+            let encode = |dictionary: Option<Arc<Dictionary>>| {
+                let options = EncodeOptions {
+                    coder,
+                    dictionary,
+                    ..EncodeOptions::default()
+                };
+                let mut encoded: Vec<u8> = Vec::new();
+                encode_stream(input.as_bytes(), &mut encoded, &options).unwrap();
+                encoded
+            };
+            let without = encode(None);
+            let encoded = encode(Some(dictionary.clone()));
+            // the table of the 30 or so bytes in a message is gone
+            assert!(encoded.len() + 40 < without.len());
+
+            let mut decoded: Vec<u8> = Vec::new();
+            let summary = decode_stream_with_dictionary(
+                encoded.as_slice(),
+                &mut decoded,
+                Some(dictionary.clone()),
+            )
+            .unwrap();
+            assert_eq!(decoded, input.as_bytes());
+            assert_eq!((summary.dictionary_blocks, summary.tables), (1, 0));
+
+            // the archive needs the same dictionary
+            let err =
+                decode_stream_with_dictionary(encoded.as_slice(), io::sink(), None).unwrap_err();
+            assert!(matches!(err, CmError::InvalidOptions(_)));
+            let other = Arc::new(Dictionary::from_counts(&HashMap::from([(b'x', 1)])));
+            let err = decode_stream_with_dictionary(encoded.as_slice(), io::sink(), Some(other))
+                .unwrap_err();
+            assert!(err.to_string().contains(&format!("{:08x}", dictionary.id)));
+        }
+    }
+}
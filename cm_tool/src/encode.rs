@@ -1,31 +1,951 @@
-use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use crc32fast::Hasher;
 
 use crate::{
-    generate_huffman_codes::generate_huffman_codes,
-    helpers::{self, load_file},
+    adaptive::AdaptiveHuffman,
+    ans::Frequencies,
+    bit_io::BitWriter,
+    dictionary::Dictionary,
+    error::CmError,
+    generate_huffman_codes::{
+        canonical_codes, generate_huffman_codes, length_limited_code_lengths,
+    },
+    header::{
+        BlockHeader, BlockKind, Coder, ContextLengths, Header, LzLengths, SeekIndex, Trailer,
+        VERSION, frequency_table_size, symbol_table_size, table_size,
+    },
+    helpers::{self, CountingWriter},
     huffman::huffman_tree,
+    lz77::{self, Token},
 };
 
-pub fn encode_and_output_file(output_path: &PathBuf, input_path: &PathBuf) {
-    // Open the file
-    let reader = load_file(&input_path);
+// Number of original bytes in each block unless told otherwise
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+// Blocks are held in memory, keep them reasonable
+pub const MAX_BLOCK_SIZE: usize = 256 << 20;
+// A batch holds a block per thread, so threads are limited too
+pub const MAX_THREADS: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    // Number of original bytes in each block
+    pub block_size: usize,
+    // Number of blocks encoded at the same time
+    pub threads: usize,
+    // One pass adaptive Huffman coding instead of a code table per block
+    pub adaptive: bool,
+    // Longest code allowed in a code table, codes are not limited when None
+    pub max_code_len: Option<u8>,
+    // Order-1 context modelling: a code table per preceding byte, for the
+    // blocks where that takes fewer bytes than a single table
+    pub context: bool,
+    // LZ77 match finding before the Huffman coding, 1 (fastest) to 9
+    // (smallest), for the blocks where it pays off. 0 turns it off.
+    pub level: u8,
+    // Entropy coder of the blocks, stored in the archive header
+    pub coder: Coder,
+    // Trained tables the blocks can use without storing a table, when
+    // that takes fewer bytes. Decoding needs the same dictionary.
+    pub dictionary: Option<Arc<Dictionary>>,
+}
+
+impl EncodeOptions {
+    // Refuses options the encoder cannot work with
+    pub fn check(&self) -> Result<(), CmError> {
+        if self.block_size == 0 || self.block_size > MAX_BLOCK_SIZE {
+            return Err(CmError::InvalidOptions(format!(
+                "block size must be between 1 and {MAX_BLOCK_SIZE} bytes"
+            )));
+        }
+        if self.threads > MAX_THREADS {
+            return Err(CmError::InvalidOptions(format!(
+                "threads must be between 1 and {MAX_THREADS}"
+            )));
+        }
+        // 256 symbols need codes of at least 8 bits
+        if let Some(max_len) = self.max_code_len
+            && !(8..=64).contains(&max_len)
+        {
+            return Err(CmError::InvalidOptions(
+                "code length limit must be between 8 and 64 bits".to_string(),
+            ));
+        }
+        if self.adaptive && self.context {
+            return Err(CmError::InvalidOptions(
+                "adaptive coding has no code tables to give a context".to_string(),
+            ));
+        }
+        if self.level > lz77::MAX_LEVEL {
+            return Err(CmError::InvalidOptions(format!(
+                "level must be between 0 and {}",
+                lz77::MAX_LEVEL
+            )));
+        }
+        if self.adaptive && self.level > 0 {
+            return Err(CmError::InvalidOptions(
+                "adaptive coding cannot be combined with LZ77 matching".to_string(),
+            ));
+        }
+        if self.coder == Coder::Ans
+            && (self.adaptive || self.context || self.level > 0 || self.max_code_len.is_some())
+        {
+            return Err(CmError::InvalidOptions(
+                "the ans coder only codes blocks with a frequency table, without --adaptive, --context, --level or --max-code-len".to_string(),
+            ));
+        }
+        if let Some(dictionary) = &self.dictionary {
+            if self.adaptive {
+                return Err(CmError::InvalidOptions(
+                    "adaptive coding has no code tables to take from a dictionary".to_string(),
+                ));
+            }
+            if let Some(max_len) = self.max_code_len
+                && dictionary.lengths.values().any(|len| *len > max_len)
+            {
+                return Err(CmError::InvalidOptions(
+                    "the dictionary has codes longer than the code length limit".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            threads: 1,
+            adaptive: false,
+            max_code_len: None,
+            context: false,
+            level: 0,
+            coder: Coder::Huffman,
+            dictionary: None,
+        }
+    }
+}
+
+pub fn encode_and_output_file(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    options: &EncodeOptions,
+    force: bool,
+) -> Result<(), CmError> {
+    // Open the file (or stdin), the output only shows up once it is complete
+    let reader = helpers::open_input(input_path)?;
+    helpers::write_output(output_path, force, |writer| {
+        encode_stream(reader, writer, options)
+    })
+}
+
+// Code table of a block, the lengths go in the block header.
+// Symbols are bytes, except for the LZ77 alphabets.
+struct BlockTable<T = u8> {
+    lengths: HashMap<T, u8>,
+    codes: HashMap<T, String>,
+}
+
+impl<T: Copy + Ord + Hash + Debug> BlockTable<T> {
+    fn new(char_map: &HashMap<T, u64>, max_code_len: Option<u8>) -> Self {
+        // get the huffman tree, there is none for an empty block
+        let huffman_tree = huffman_tree::<T>(char_map.clone());
+
+        // generate the canonical huffman codes
+        let codes: HashMap<T, String> =
+            huffman_tree.map(generate_huffman_codes).unwrap_or_default();
+        let lengths: HashMap<T, u8> = codes
+            .iter()
+            .map(|(byte, code)| (*byte, code.len() as u8))
+            .collect();
+
+        // Fall back to length limited codes when a code is too long
+        if let Some(max_len) = max_code_len
+            && lengths.values().any(|len| *len > max_len)
+        {
+            let lengths = length_limited_code_lengths(char_map, max_len)
+                .expect("the code length limit was checked before encoding");
+            let codes = canonical_codes(&lengths);
+            return BlockTable { lengths, codes };
+        }
+
+        BlockTable { lengths, codes }
+    }
+
+    fn from_lengths(lengths: HashMap<T, u8>) -> Self {
+        let codes = canonical_codes(&lengths);
+        BlockTable { lengths, codes }
+    }
+
+    // Bits needed to encode the counted bytes with this table, None if a byte has no code
+    fn cost(&self, char_map: &HashMap<T, u64>) -> Option<u64> {
+        if char_map.keys().all(|byte| self.codes.contains_key(byte)) {
+            Some(helpers::total_bits(char_map, &self.codes))
+        } else {
+            None
+        }
+    }
+}
+
+// Order-1 code tables of a block: one per preceding byte, built from the
+// bytes that follow it. The first byte of the block follows context 0.
+struct ContextTables {
+    // Indexed by the preceding byte, None for bytes that precede nothing
+    tables: Vec<Option<BlockTable>>,
+    // Bits of the payload and of the tables once written
+    cost: u64,
+}
+
+impl ContextTables {
+    fn new(data: &[u8], max_code_len: Option<u8>) -> Self {
+        // Count every (preceding byte, byte) pair, a row only for the
+        // contexts that show up so small blocks stay cheap
+        let mut counts: Vec<Vec<u64>> = vec![Vec::new(); 256];
+        let mut context = 0usize;
+        for byte in data {
+            let row = &mut counts[context];
+            if row.is_empty() {
+                row.resize(256, 0);
+            }
+            row[*byte as usize] += 1;
+            context = *byte as usize;
+        }
+
+        // number of tables
+        let mut cost = 16;
+        let tables = counts
+            .iter()
+            .map(|row| {
+                if row.is_empty() {
+                    return None;
+                }
+                let char_map: HashMap<u8, u64> = (0..=255u8)
+                    .map(|byte| (byte, row[byte as usize]))
+                    .filter(|(_, count)| *count > 0)
+                    .collect();
+                let table = BlockTable::new(&char_map, max_code_len);
+                // context byte, table and codes
+                cost += 8 + table_size(&table.lengths) * 8 + table.cost(&char_map).unwrap_or(0);
+                Some(table)
+            })
+            .collect();
+
+        ContextTables { tables, cost }
+    }
+
+    // Code lengths of every table, as the block header stores them
+    fn contexts(&self) -> ContextLengths {
+        (0..=255u8)
+            .zip(&self.tables)
+            .filter_map(|(context, table)| Some((context, table.as_ref()?.lengths.clone())))
+            .collect()
+    }
+
+    fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let codes: Vec<Vec<(u64, u8)>> = self
+            .tables
+            .iter()
+            .map(|table| {
+                table
+                    .as_ref()
+                    .map(|table| helpers::pack_codes(&table.codes))
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let mut bit_writer = BitWriter::new(Vec::new());
+        let mut context = 0usize;
+        for byte in data {
+            let (bits, len) = codes[context][*byte as usize];
+            bit_writer.write_bits(bits, len)?;
+            context = *byte as usize;
+        }
+        bit_writer.finish()
+    }
+}
+
+// LZ77 tokens of a block, with a code table for the literal and length
+// symbols and one for the distance symbols
+struct LzBlock {
+    tokens: Vec<Token>,
+    literals: BlockTable<u16>,
+    distances: BlockTable<u16>,
+    // Bits of the payload and of the tables once written
+    cost: u64,
+}
+
+impl LzBlock {
+    fn new(data: &[u8], level: u8, max_code_len: Option<u8>) -> Self {
+        let tokens = lz77::find_matches(data, level);
+
+        // Count the symbols, the extra bits are sent as they are
+        let mut literal_counts: HashMap<u16, u64> = HashMap::new();
+        let mut distance_counts: HashMap<u16, u64> = HashMap::new();
+        let mut extra_bits = 0u64;
+        for token in &tokens {
+            match *token {
+                Token::Literal(byte) => *literal_counts.entry(byte as u16).or_default() += 1,
+                Token::Match { length, distance } => {
+                    let (symbol, _, length_bits) = lz77::length_symbol(length);
+                    *literal_counts.entry(symbol).or_default() += 1;
+                    let (symbol, _, distance_bits) = lz77::distance_symbol(distance);
+                    *distance_counts.entry(symbol).or_default() += 1;
+                    extra_bits += (length_bits + distance_bits) as u64;
+                }
+            }
+        }
+
+        // 286 literal/length symbols need codes of at least 9 bits
+        let max_code_len = max_code_len.map(|max_len| max_len.max(9));
+        let literals = BlockTable::new(&literal_counts, max_code_len);
+        let distances = BlockTable::new(&distance_counts, max_code_len);
+        let cost = (symbol_table_size(&literals.lengths) + symbol_table_size(&distances.lengths))
+            * 8
+            + literals.cost(&literal_counts).unwrap_or(0)
+            + distances.cost(&distance_counts).unwrap_or(0)
+            + extra_bits;
+
+        LzBlock {
+            tokens,
+            literals,
+            distances,
+            cost,
+        }
+    }
+
+    fn lengths(&self) -> LzLengths {
+        LzLengths {
+            literals: self.literals.lengths.clone(),
+            distances: self.distances.lengths.clone(),
+        }
+    }
+
+    // Each match is its length symbol and extra bits, then its distance symbol and extra bits
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let literals = helpers::pack_codes(&self.literals.codes);
+        let distances = helpers::pack_codes(&self.distances.codes);
+
+        let mut bit_writer = BitWriter::new(Vec::new());
+        for token in &self.tokens {
+            match *token {
+                Token::Literal(byte) => {
+                    let (bits, len) = literals[byte as usize];
+                    bit_writer.write_bits(bits, len)?;
+                }
+                Token::Match { length, distance } => {
+                    let (symbol, extra, extra_len) = lz77::length_symbol(length);
+                    let (bits, len) = literals[symbol as usize];
+                    bit_writer.write_bits(bits, len)?;
+                    bit_writer.write_bits(extra as u64, extra_len)?;
+
+                    let (symbol, extra, extra_len) = lz77::distance_symbol(distance);
+                    let (bits, len) = distances[symbol as usize];
+                    bit_writer.write_bits(bits, len)?;
+                    bit_writer.write_bits(extra as u64, extra_len)?;
+                }
+            }
+        }
+        bit_writer.finish()
+    }
+}
+
+// How a static block is coded
+enum Coding {
+    // A single table, sent with the block or reused from the previous one
+    Table { table: Arc<BlockTable>, reuse: bool },
+    // Order-1 tables, sent with the block
+    Context(ContextTables),
+    // LZ77 literals and matches, with their tables
+    Lz77(LzBlock),
+    // The table of the dictionary, only its ID is sent
+    Dictionary(Arc<BlockTable>),
+}
+
+// Compresses everything written to it into `writer` as a .huff archive.
+//
+// Bytes are gathered into blocks and encoded once a batch of them is full,
+// so the output does not depend on how the data was split into writes.
+// `finish` must be called to encode the last block and close the archive.
+pub struct HuffmanEncoder<W: Write> {
+    writer: CountingWriter<W>,
+    options: EncodeOptions,
+    header_written: bool,
+    // Bytes not encoded yet, at most one batch of blocks
+    pending: Vec<u8>,
+    // Size and checksum of everything written so far, for the trailer
+    original_len: u64,
+    hasher: Hasher,
+    // Table of the last block, the next one may reuse it
+    previous: Option<Arc<BlockTable>>,
+    previous_frequencies: Option<Arc<Frequencies>>,
+    // Adaptive model, carried on from one block to the next
+    model: AdaptiveHuffman,
+    // Where every block went, written at the end of the archive
+    index: SeekIndex,
+}
+
+impl<W: Write> HuffmanEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_options(writer, EncodeOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: EncodeOptions) -> Self {
+        HuffmanEncoder {
+            writer: CountingWriter {
+                inner: writer,
+                count: 0,
+            },
+            options,
+            header_written: false,
+            pending: Vec::new(),
+            original_len: 0,
+            hasher: Hasher::new(),
+            previous: None,
+            previous_frequencies: None,
+            model: AdaptiveHuffman::new(),
+            index: SeekIndex::default(),
+        }
+    }
+
+    // Encodes what is left, closes the archive and returns the writer
+    pub fn finish(mut self) -> Result<W, CmError> {
+        self.write_header()?;
+        if !self.pending.is_empty() {
+            self.encode_pending()?;
+        }
+
+        // Close the stream with the size and checksum of the input
+        BlockHeader::end().write(&mut self.writer)?;
+        Trailer {
+            original_len: self.original_len,
+            checksum: self.hasher.clone().finalize(),
+        }
+        .write(&mut self.writer)?;
+        let index_offset = self.writer.count;
+        self.index.write(&mut self.writer, index_offset)?;
+        self.writer.flush()?;
+        Ok(self.writer.inner)
+    }
+
+    // Static blocks are encoded `threads` at a time, adaptive ones one by one
+    fn batch_size(&self) -> usize {
+        if self.options.adaptive {
+            self.options.block_size
+        } else {
+            self.options
+                .block_size
+                .saturating_mul(self.options.threads.max(1))
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), CmError> {
+        if !self.header_written {
+            self.options.check()?;
+            Header {
+                block_size: self.options.block_size as u32,
+                coder: self.options.coder,
+                version: VERSION,
+            }
+            .write(&mut self.writer)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    fn encode_pending(&mut self) -> Result<(), CmError> {
+        if self.options.adaptive {
+            encode_adaptive_block(
+                &mut self.model,
+                &self.pending,
+                &mut self.writer,
+                &mut self.index,
+            )?;
+        } else if self.options.coder == Coder::Ans {
+            encode_ans_blocks(
+                &self.pending,
+                &mut self.previous_frequencies,
+                &mut self.writer,
+                &mut self.index,
+                &self.options,
+            )?;
+        } else {
+            encode_static_blocks(
+                &self.pending,
+                &mut self.previous,
+                &mut self.writer,
+                &mut self.index,
+                &self.options,
+            )?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HuffmanEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+
+        // Take what fits in the current batch, encode it once it is full
+        let batch_size = self.batch_size();
+        let len = buf.len().min(batch_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        self.hasher.update(&buf[..len]);
+        self.original_len += len as u64;
+        if self.pending.len() == batch_size {
+            self.encode_pending()?;
+        }
+        Ok(len)
+    }
+
+    // Only flushes the inner writer: a block is encoded once it is full
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Encodes `reader` into `writer` in a single pass, one block at a time
+pub fn encode_stream<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &EncodeOptions,
+) -> Result<(), CmError> {
+    let mut encoder = HuffmanEncoder::with_options(BufWriter::new(writer), options.clone());
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+// Each block gets its own code table, unless the previous block's table
+// encodes it in fewer bits than a new table would take with its header.
+// With order-1 contexts enabled, blocks where the context tables take
+// fewer bits than either are coded with them instead, and the same goes
+// for LZ77 matching when a level is set, and for the dictionary's table.
+//
+// With several threads, `data` holds up to `threads` blocks: their tables
+// are built and their bits packed on worker threads, while the choice of
+// reusing a table is made in block order on this thread, so the output is
+// the same whatever the number of threads.
+fn encode_static_blocks<W: Write>(
+    data: &[u8],
+    previous: &mut Option<Arc<BlockTable>>,
+    writer: &mut CountingWriter<W>,
+    index: &mut SeekIndex,
+    options: &EncodeOptions,
+) -> Result<(), CmError> {
+    let blocks: Vec<&[u8]> = data.chunks(options.block_size).collect();
+
+    // Get the byte ocurrences and the tables of every block
+    type Counted = (
+        HashMap<u8, u64>,
+        BlockTable,
+        Option<ContextTables>,
+        Option<LzBlock>,
+    );
+    let counted: Vec<io::Result<Counted>> = parallel_map(&blocks, options.threads, |data| {
+        let char_map: HashMap<u8, u64> = helpers::get_char_occurence(*data)?;
+        let table = BlockTable::new(&char_map, options.max_code_len);
+        let contexts = options
+            .context
+            .then(|| ContextTables::new(data, options.max_code_len));
+        let lz =
+            (options.level > 0).then(|| LzBlock::new(data, options.level, options.max_code_len));
+        Ok((char_map, table, contexts, lz))
+    });
+
+    let dictionary = options.dictionary.as_ref().map(|dictionary| {
+        (
+            dictionary.id,
+            Arc::new(BlockTable::from_lengths(dictionary.lengths.clone())),
+        )
+    });
+
+    // Reuse the previous table when that is cheaper than sending this one,
+    // and use the dictionary, context tables or LZ77 when they are cheaper still
+    let mut jobs: Vec<(&[u8], Coding)> = Vec::with_capacity(blocks.len());
+    for (data, counted) in blocks.iter().zip(counted) {
+        let (char_map, table, contexts, lz) = counted?;
+        let new_cost = table.cost(&char_map).unwrap_or(0) + table_size(&table.lengths) * 8;
+        let reuse_cost = previous
+            .as_ref()
+            .and_then(|previous| previous.cost(&char_map))
+            .filter(|reuse_cost| *reuse_cost <= new_cost);
+        let table_cost = reuse_cost.unwrap_or(new_cost);
+        // the ID is sent instead of a table
+        let dictionary_cost = dictionary
+            .as_ref()
+            .and_then(|(_, table)| table.cost(&char_map))
+            .map_or(u64::MAX, |cost| cost + 32);
+        let single_cost = table_cost.min(dictionary_cost);
+        let context_cost = contexts.as_ref().map_or(u64::MAX, |contexts| contexts.cost);
+
+        if let Some(lz) = lz
+            && lz.cost < single_cost.min(context_cost)
+        {
+            jobs.push((data, Coding::Lz77(lz)));
+            continue;
+        }
+        if let Some(contexts) = contexts
+            && contexts.cost < single_cost
+        {
+            jobs.push((data, Coding::Context(contexts)));
+            continue;
+        }
+        if let Some((_, table)) = &dictionary
+            && dictionary_cost < table_cost
+        {
+            jobs.push((data, Coding::Dictionary(table.clone())));
+            continue;
+        }
+        let reuse = reuse_cost.is_some();
+        if !reuse {
+            *previous = Some(Arc::new(table));
+        }
+        let table = previous.clone().expect("a table was just stored");
+        jobs.push((data, Coding::Table { table, reuse }));
+    }
+
+    // Encode the blocks
+    let payloads: Vec<io::Result<Vec<u8>>> =
+        parallel_map(&jobs, options.threads, |(data, coding)| match coding {
+            Coding::Table { table, .. } | Coding::Dictionary(table) => {
+                let mut payload: Vec<u8> = Vec::new();
+                helpers::encoder(*data, &table.codes, &mut payload)?;
+                Ok(payload)
+            }
+            Coding::Context(contexts) => contexts.encode(data),
+            Coding::Lz77(lz) => lz.encode(),
+        });
+
+    // Write them out in order
+    for ((data, coding), payload) in jobs.iter().zip(payloads) {
+        let payload = payload?;
+        let (kind, lengths, contexts, lz) = match coding {
+            Coding::Table { reuse: true, .. } => (BlockKind::ReuseTable, None, None, None),
+            Coding::Table { table, .. } => {
+                (BlockKind::NewTable, Some(table.lengths.clone()), None, None)
+            }
+            Coding::Context(contexts) => {
+                (BlockKind::Context, None, Some(contexts.contexts()), None)
+            }
+            Coding::Lz77(lz) => (BlockKind::Lz77, None, None, Some(lz.lengths())),
+            Coding::Dictionary(_) => (BlockKind::Dictionary, None, None, None),
+        };
+        let block_header = BlockHeader {
+            kind,
+            raw_len: data.len() as u32,
+            lengths,
+            frequencies: None,
+            contexts,
+            lz,
+            dictionary: dictionary
+                .as_ref()
+                .filter(|_| kind == BlockKind::Dictionary)
+                .map(|(id, _)| *id),
+            payload_len: payload.len() as u32,
+        };
+        write_block(writer, index, &block_header, &payload)?;
+    }
+    Ok(())
+}
+
+// Same as encode_static_blocks with the rANS coder: every block gets its
+// own frequency table, unless the previous block's one or the dictionary's
+// costs fewer bits
+fn encode_ans_blocks<W: Write>(
+    data: &[u8],
+    previous: &mut Option<Arc<Frequencies>>,
+    writer: &mut CountingWriter<W>,
+    index: &mut SeekIndex,
+    options: &EncodeOptions,
+) -> Result<(), CmError> {
+    let blocks: Vec<&[u8]> = data.chunks(options.block_size).collect();
+
+    let counted: Vec<io::Result<(HashMap<u8, u64>, Frequencies)>> =
+        parallel_map(&blocks, options.threads, |data| {
+            let char_map: HashMap<u8, u64> = helpers::get_char_occurence(*data)?;
+            let frequencies = Frequencies::new(&char_map);
+            Ok((char_map, frequencies))
+        });
+
+    let dictionary = match &options.dictionary {
+        Some(dictionary) => Some((
+            dictionary.id,
+            Arc::new(Frequencies::from_table(&dictionary.frequencies)?),
+        )),
+        None => None,
+    };
+
+    let mut jobs: Vec<(&[u8], Arc<Frequencies>, BlockKind)> = Vec::with_capacity(blocks.len());
+    for (data, counted) in blocks.iter().zip(counted) {
+        let (char_map, frequencies) = counted?;
+        let new_cost = frequencies.cost(&char_map).unwrap_or(0)
+            + frequency_table_size(&frequencies.table()) * 8;
+        let reuse_cost = previous
+            .as_ref()
+            .and_then(|previous| previous.cost(&char_map))
+            .filter(|reuse_cost| *reuse_cost <= new_cost);
+        let dictionary_cost = dictionary
+            .as_ref()
+            .and_then(|(_, frequencies)| frequencies.cost(&char_map))
+            .map_or(u64::MAX, |cost| cost + 32);
+
+        if let Some((_, frequencies)) = &dictionary
+            && dictionary_cost < reuse_cost.unwrap_or(new_cost)
+        {
+            jobs.push((data, frequencies.clone(), BlockKind::Dictionary));
+            continue;
+        }
+        let kind = match reuse_cost {
+            Some(_) => BlockKind::ReuseTable,
+            None => {
+                *previous = Some(Arc::new(frequencies));
+                BlockKind::NewTable
+            }
+        };
+        let frequencies = previous.clone().expect("a table was just stored");
+        jobs.push((data, frequencies, kind));
+    }
+
+    let payloads: Vec<Vec<u8>> = parallel_map(&jobs, options.threads, |(data, frequencies, _)| {
+        frequencies.encode(data)
+    });
+
+    for ((data, frequencies, kind), payload) in jobs.iter().zip(payloads) {
+        let block_header = BlockHeader {
+            kind: *kind,
+            raw_len: data.len() as u32,
+            lengths: None,
+            frequencies: (*kind == BlockKind::NewTable).then(|| frequencies.table()),
+            contexts: None,
+            lz: None,
+            dictionary: dictionary
+                .as_ref()
+                .filter(|_| *kind == BlockKind::Dictionary)
+                .map(|(id, _)| *id),
+            payload_len: payload.len() as u32,
+        };
+        write_block(writer, index, &block_header, &payload)?;
+    }
+    Ok(())
+}
+
+// Adaptive blocks carry no table: the model learns from every byte and
+// carries on from one block to the next, so the blocks are encoded in order
+fn encode_adaptive_block<W: Write>(
+    model: &mut AdaptiveHuffman,
+    block: &[u8],
+    writer: &mut CountingWriter<W>,
+    index: &mut SeekIndex,
+) -> Result<(), CmError> {
+    let mut bit_writer = BitWriter::new(Vec::new());
+    for byte in block {
+        model.encode(*byte, &mut bit_writer)?;
+    }
+    let payload = bit_writer.finish()?;
+
+    let block_header = BlockHeader {
+        kind: BlockKind::Adaptive,
+        raw_len: block.len() as u32,
+        lengths: None,
+        frequencies: None,
+        contexts: None,
+        lz: None,
+        dictionary: None,
+        payload_len: payload.len() as u32,
+    };
+    write_block(writer, index, &block_header, &payload)?;
+    Ok(())
+}
+
+// Writes a block and adds it to the seek index
+fn write_block<W: Write>(
+    writer: &mut CountingWriter<W>,
+    index: &mut SeekIndex,
+    block_header: &BlockHeader,
+    payload: &[u8],
+) -> io::Result<()> {
+    index.push(block_header, writer.count);
+    block_header.write(writer)?;
+    writer.write_all(payload)
+}
+
+// Applies `f` to every item on a pool of `threads` workers, keeping the order
+fn parallel_map<T: Sync, U: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> U + Sync,
+) -> Vec<U> {
+    let workers = threads.min(items.len());
+    if workers <= 1 {
+        return items.iter().map(f).collect();
+    }
+    // Each worker takes the next item until there are none left
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, U)> = thread::scope(|scope| {
+        let (f, next) = (&f, &next);
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut done: Vec<(usize, U)> = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("encoding thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(input: &[u8], block_size: usize, threads: usize) -> Vec<u8> {
+        let options = EncodeOptions {
+            block_size,
+            threads,
+            ..EncodeOptions::default()
+        };
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input, &mut encoded, &options).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn test_output_does_not_depend_on_threads() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        // mix in binary data so some blocks need a new table and others reuse one
+        let mut input = text[..300_000].to_vec();
+        input.extend(
+            std::fs::read(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/sample.bin"
+            ))
+            .unwrap(),
+        );
+        input.extend(&text[300_000..600_000]);
+
+        let single = encode(&input, 16 * 1024, 1);
+        for threads in [2, 3, 4, 8, 64] {
+            assert_eq!(
+                encode(&input, 16 * 1024, threads),
+                single,
+                "{threads} threads"
+            );
+        }
+    }
+
+    #[test]
+    fn test_threads_are_limited() {
+        let options = EncodeOptions {
+            threads: MAX_THREADS + 1,
+            ..EncodeOptions::default()
+        };
+        assert!(matches!(options.check(), Err(CmError::InvalidOptions(_))));
+
+        // fewer workers than items, the results stay in order
+        let items: Vec<usize> = (0..100).collect();
+        let squares = parallel_map(&items, 3, |i| i * i);
+        assert_eq!(squares, items.iter().map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_max_code_len_is_respected() {
+        // Fibonacci frequencies make the Huffman code as deep as it gets
+        let (mut a, mut b) = (1usize, 1usize);
+        let mut input: Vec<u8> = Vec::new();
+        for byte in 0..24u8 {
+            input.extend(std::iter::repeat_n(byte, a));
+            (a, b) = (b, a + b);
+        }
+
+        let char_map = helpers::get_char_occurence(input.as_slice()).unwrap();
+        let unlimited = BlockTable::new(&char_map, None);
+        assert_eq!(unlimited.lengths.values().max(), Some(&23));
+
+        let limited = BlockTable::new(&char_map, Some(10));
+        assert_eq!(limited.lengths.values().max(), Some(&10));
+
+        // and the data still round trips
+        let options = EncodeOptions {
+            max_code_len: Some(10),
+            ..EncodeOptions::default()
+        };
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input.as_slice(), &mut encoded, &options).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        crate::decode::decode_stream(encoded.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_output_does_not_depend_on_writes() {
+        let input: Vec<u8> = b"written a few bytes at a time. ".repeat(200);
+        let options = EncodeOptions {
+            block_size: 512,
+            threads: 3,
+            ..EncodeOptions::default()
+        };
 
-    // Get the char ocurrences
-    let char_map: HashMap<char, u64> = helpers::get_char_occurence(reader);
+        let mut encoder = HuffmanEncoder::with_options(Vec::new(), options.clone());
+        for chunk in input.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let encoded = encoder.finish().unwrap();
 
-    // get the huffman tree
-    let huffman_tree = huffman_tree::<char>(char_map);
+        let mut expected: Vec<u8> = Vec::new();
+        encode_stream(input.as_slice(), &mut expected, &options).unwrap();
+        assert_eq!(encoded, expected);
+    }
 
-    // generate the huffman codes
-    let char_codes: HashMap<char, String> = generate_huffman_codes(huffman_tree);
+    #[test]
+    fn test_context_tables_shrink_text() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let order_0 = encode(&text, DEFAULT_BLOCK_SIZE, 1);
+        let options = EncodeOptions {
+            context: true,
+            ..EncodeOptions::default()
+        };
+        let mut order_1: Vec<u8> = Vec::new();
+        encode_stream(text.as_slice(), &mut order_1, &options).unwrap();
 
-    // Write header char codes to file
-    helpers::write_header_to_file(output_path, &char_codes);
+        // about 58% down to 44%
+        assert!(order_1.len() < order_0.len() * 4 / 5);
 
-    // Write the main content to file (encoded with huffman codes)
-    // Open the file
-    let reader: BufReader<File> = load_file(&input_path);
+        let mut decoded: Vec<u8> = Vec::new();
+        crate::decode::decode_stream(order_1.as_slice(), &mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
 
-    // Encode the file
-    helpers::encoder(reader, &char_codes, output_path);
+    #[test]
+    fn test_input_of_exactly_whole_blocks() {
+        // the last read of a batch comes back empty
+        let input = b"0123456789abcdef".repeat(64);
+        for threads in [1, 2, 4] {
+            assert_eq!(encode(&input, 256, threads), encode(&input, 256, 1));
+        }
+    }
 }
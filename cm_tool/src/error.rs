@@ -0,0 +1,136 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+};
+
+// Everything that can go wrong while encoding or decoding
+#[derive(Debug)]
+pub enum CmError {
+    // Reading the input or writing the output failed
+    Io(io::Error),
+    // Not an archive, or one that is corrupt or cut short
+    Format(String),
+    // An archive from another version of the format
+    UnsupportedVersion(u8),
+    // A block of a kind added after this version of cm_tool
+    UnsupportedBlockKind(u8),
+    // The decoded data does not match the checksum stored in the archive
+    Checksum { expected: u32, actual: u32 },
+    // Encoding options that cannot work, e.g. a block size of 0
+    InvalidOptions(String),
+}
+
+impl CmError {
+    pub fn format(message: impl Into<String>) -> Self {
+        CmError::Format(message.into())
+    }
+
+    // Exit code of the command line tool, so scripts can tell failures apart
+    // (2 is taken by command line usage errors)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CmError::Io(e) if e.kind() == ErrorKind::NotFound => 3,
+            CmError::Io(e) if e.kind() == ErrorKind::PermissionDenied => 4,
+            CmError::Io(_) => 1,
+            CmError::Format(_) => 5,
+            CmError::Checksum { .. } => 6,
+            CmError::UnsupportedVersion(_) | CmError::UnsupportedBlockKind(_) => 7,
+            CmError::InvalidOptions(_) => 2,
+        }
+    }
+
+    // A read of archive data hitting the end of the input means the archive was cut short
+    pub fn truncated(e: io::Error) -> Self {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            CmError::format("archive is truncated")
+        } else {
+            CmError::from(e)
+        }
+    }
+}
+
+impl Display for CmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CmError::Io(e) => write!(f, "{e}"),
+            CmError::Format(message) => write!(f, "{message}"),
+            CmError::UnsupportedVersion(version) => {
+                write!(f, "unsupported archive version {version}")
+            }
+            CmError::UnsupportedBlockKind(kind) => write!(
+                f,
+                "unsupported block kind {kind}, the archive needs a newer cm_tool"
+            ),
+            CmError::Checksum { expected, actual } => write!(
+                f,
+                "archive is corrupt: checksum mismatch (expected {expected:08x}, got {actual:08x})"
+            ),
+            CmError::InvalidOptions(message) => write!(f, "invalid options: {message}"),
+        }
+    }
+}
+
+impl Error for CmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The Read and Write adapters can only return io::Error, so a CmError
+// travels inside one and is taken back out on the other side
+impl From<io::Error> for CmError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<CmError>()) {
+            let inner = e.into_inner().expect("checked above");
+            return *inner.downcast::<CmError>().expect("checked above");
+        }
+        CmError::Io(e)
+    }
+}
+
+impl From<CmError> for io::Error {
+    fn from(e: CmError) -> Self {
+        match e {
+            CmError::Io(e) => e,
+            CmError::InvalidOptions(_) => io::Error::new(ErrorKind::InvalidInput, e),
+            e => io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_survives_a_trip_through_io_error() {
+        let error = CmError::Checksum {
+            expected: 1,
+            actual: 2,
+        };
+        let io_error = io::Error::from(error);
+        assert_eq!(io_error.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            CmError::from(io_error),
+            CmError::Checksum {
+                expected: 1,
+                actual: 2
+            }
+        ));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let missing = CmError::from(io::Error::from(ErrorKind::NotFound));
+        assert_eq!(missing.exit_code(), 3);
+        assert_eq!(CmError::format("archive is corrupt").exit_code(), 5);
+        assert_eq!(
+            CmError::truncated(io::Error::from(ErrorKind::UnexpectedEof)).exit_code(),
+            5
+        );
+    }
+}
@@ -3,13 +3,143 @@
 use crate::huffman::Tree;
 use std::{collections::HashMap, hash::Hash};
 
-pub fn generate_huffman_codes<T: Clone + Eq + Hash>(huffman_tree: Tree<T>) -> HashMap<T, String> {
+// Canonical Huffman codes only depend on the code length of every char:
+// chars are sorted by (length, char) and given consecutive codes, so a
+// decoder can rebuild the exact same codes from the lengths alone
+pub fn generate_huffman_codes<T: Clone + Ord + Hash>(huffman_tree: Tree<T>) -> HashMap<T, String> {
+    canonical_codes(&code_lengths(huffman_tree))
+}
+
+// Length of the code of each char, taken from its depth in the tree
+pub fn code_lengths<T: Clone + Eq + Hash>(huffman_tree: Tree<T>) -> HashMap<T, u8> {
+    // A lone char is at the root, give it a one bit code so every char
+    // still takes some bits and the decoder can count them
+    if let Tree::Leaf { char, .. } = huffman_tree {
+        return HashMap::from([(char, 1)]);
+    }
+
     // keep track of the bits and store when a char is met
     let mut codes_char: HashMap<T, String> = HashMap::new();
     let mut codes = String::new();
     // Get the codes
     get_code::<T>(huffman_tree, &mut codes, &mut codes_char);
     codes_char
+        .into_iter()
+        .map(|(char, code)| (char, code.len() as u8))
+        .collect()
+}
+
+pub fn canonical_codes<T: Clone + Ord + Hash>(lengths: &HashMap<T, u8>) -> HashMap<T, String> {
+    let mut sorted: Vec<(u8, &T)> = lengths.iter().map(|(char, len)| (*len, char)).collect();
+    sorted.sort();
+
+    let mut codes_char: HashMap<T, String> = HashMap::new();
+    let mut code: u64 = 0;
+    let mut prev_len: u8 = 0;
+    for (i, (len, char)) in sorted.into_iter().enumerate() {
+        // Next code of the same length is code + 1,
+        // moving to a longer length appends zeros
+        if i > 0 {
+            code += 1;
+        }
+        // The first code is all zeros, and may be 64 bits long:
+        // shifting it by 64 would overflow
+        if code > 0 {
+            code <<= len - prev_len;
+        }
+        prev_len = len;
+
+        codes_char.insert(
+            char.clone(),
+            format!("{:0width$b}", code, width = len as usize),
+        );
+    }
+    codes_char
+}
+
+// Code lengths of an optimal prefix code whose codes are at most `max_len`
+// bits long, found with the package-merge algorithm. Returns None when
+// there are too many chars for codes that short.
+//
+// Every char is a coin of its frequency at each of the `max_len` levels.
+// Going from the deepest level up, the cheapest coins are paired into
+// packages that are merged with the coins of the level above. The
+// 2 * (n - 1) cheapest items of the last list are kept, and the length of a
+// char's code is the number of kept items (packages included) holding it.
+pub fn length_limited_code_lengths<T: Clone + Ord + Hash>(
+    char_map: &HashMap<T, u64>,
+    max_len: u8,
+) -> Option<HashMap<T, u8>> {
+    let mut chars: Vec<(u64, T)> = char_map
+        .iter()
+        .map(|(char, freq)| (*freq, char.clone()))
+        .collect();
+    chars.sort();
+
+    let n = chars.len();
+    if n <= 1 {
+        return Some(chars.into_iter().map(|(_, char)| (char, 1)).collect());
+    }
+    if max_len < 64 && n as u64 > 1u64 << max_len {
+        return None;
+    }
+
+    // Items are the chars themselves, or packages of two items
+    enum Item {
+        Char(usize),
+        Package(usize, usize),
+    }
+    let mut items: Vec<Item> = (0..n).map(Item::Char).collect();
+    let mut weights: Vec<u64> = chars.iter().map(|(freq, _)| *freq).collect();
+
+    let coins: Vec<usize> = (0..n).collect();
+    let mut list: Vec<usize> = coins.clone();
+    for _ in 1..max_len {
+        // Pair up the cheapest items of the level
+        let mut packages: Vec<usize> = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks_exact(2) {
+            items.push(Item::Package(pair[0], pair[1]));
+            weights.push(weights[pair[0]] + weights[pair[1]]);
+            packages.push(items.len() - 1);
+        }
+
+        // Merge them with the coins of the next level, both are sorted
+        let mut merged: Vec<usize> = Vec::with_capacity(n + packages.len());
+        let (mut c, mut p) = (0, 0);
+        while c < coins.len() || p < packages.len() {
+            let take_coin = p == packages.len()
+                || (c < coins.len() && weights[coins[c]] <= weights[packages[p]]);
+            if take_coin {
+                merged.push(coins[c]);
+                c += 1;
+            } else {
+                merged.push(packages[p]);
+                p += 1;
+            }
+        }
+        list = merged;
+    }
+
+    // Count how many kept items hold each char
+    let mut lengths = vec![0u8; n];
+    let mut stack: Vec<usize> = list[..2 * (n - 1)].to_vec();
+    while let Some(item) = stack.pop() {
+        match items[item] {
+            Item::Char(char) => lengths[char] += 1,
+            Item::Package(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+        }
+    }
+
+    Some(
+        chars
+            .into_iter()
+            .zip(lengths)
+            .map(|((_, char), len)| (char, len))
+            .collect(),
+    )
 }
 
 pub fn get_code<T: Clone + Eq + Hash>(
@@ -54,7 +184,7 @@ mod test {
         char_map.insert('m', 24);
 
         // Build a huffman tree
-        let tree = huffman_tree(char_map);
+        let tree = huffman_tree(char_map).unwrap();
         let mut codes_char: HashMap<char, String> = HashMap::new();
         let mut code = String::new();
 
@@ -102,4 +232,112 @@ mod test {
             "Mismatched Values"
         );
     }
+
+    #[test]
+    fn test_canonical_codes() {
+        let mut char_map: HashMap<char, u64> = HashMap::new();
+        char_map.insert('e', 120);
+        char_map.insert('u', 37);
+        char_map.insert('d', 42);
+        char_map.insert('l', 42);
+        char_map.insert('c', 32);
+        char_map.insert('z', 2);
+        char_map.insert('k', 7);
+        char_map.insert('m', 24);
+
+        let codes_char = generate_huffman_codes(huffman_tree(char_map).unwrap());
+
+        // Same lengths as the tree codes, assigned in (length, char) order
+        let expected = [
+            ('e', "0"),
+            ('d', "100"),
+            ('l', "101"),
+            ('u', "110"),
+            ('c', "1110"),
+            ('m', "11110"),
+            ('k', "111110"),
+            ('z', "111111"),
+        ];
+        for (char, code) in expected {
+            assert_eq!(codes_char.get(&char).unwrap(), code, "Mismatched Values");
+        }
+    }
+
+    // Frequencies 1, 1, 2, 3, 5, 8... give the deepest possible Huffman tree
+    fn fibonacci_map(n: usize) -> HashMap<u8, u64> {
+        let (mut a, mut b) = (1u64, 1u64);
+        (0..n as u8)
+            .map(|char| {
+                let freq = a;
+                (a, b) = (b, a + b);
+                (char, freq)
+            })
+            .collect()
+    }
+
+    // Sum of 2^-length over all codes, exactly 1 for a complete prefix code
+    fn kraft_sum(lengths: &HashMap<u8, u8>) -> f64 {
+        lengths.values().map(|len| 0.5f64.powi(*len as i32)).sum()
+    }
+
+    fn cost(char_map: &HashMap<u8, u64>, lengths: &HashMap<u8, u8>) -> u64 {
+        char_map
+            .iter()
+            .map(|(char, freq)| freq * lengths[char] as u64)
+            .sum()
+    }
+
+    #[test]
+    fn test_length_limited_codes_on_fibonacci_frequencies() {
+        let char_map = fibonacci_map(30);
+        let huffman = code_lengths(huffman_tree(char_map.clone()).unwrap());
+        assert_eq!(huffman.values().max(), Some(&29));
+
+        for max_len in [5u8, 8, 12, 15, 20] {
+            let lengths = length_limited_code_lengths(&char_map, max_len).unwrap();
+            assert_eq!(lengths.len(), 30);
+            assert!(lengths.values().all(|len| (1..=max_len).contains(len)));
+            assert!((kraft_sum(&lengths) - 1.0).abs() < 1e-12);
+            // never better than the unlimited Huffman code
+            assert!(cost(&char_map, &lengths) >= cost(&char_map, &huffman));
+        }
+    }
+
+    #[test]
+    fn test_length_limit_above_huffman_depth_is_optimal() {
+        let char_map = fibonacci_map(10);
+        let huffman = code_lengths(huffman_tree(char_map.clone()).unwrap());
+        let lengths = length_limited_code_lengths(&char_map, 20).unwrap();
+        assert_eq!(cost(&char_map, &lengths), cost(&char_map, &huffman));
+    }
+
+    #[test]
+    fn test_length_limit_too_short_for_chars() {
+        // 30 chars do not fit in 4 bit codes
+        assert_eq!(length_limited_code_lengths(&fibonacci_map(30), 4), None);
+        assert!(length_limited_code_lengths(&fibonacci_map(16), 4).is_some());
+    }
+
+    #[test]
+    fn test_single_char_gets_a_one_bit_code() {
+        let char_map = HashMap::from([(b'a', 1000u64)]);
+        let codes_char = generate_huffman_codes(huffman_tree(char_map.clone()).unwrap());
+        assert_eq!(codes_char.get(&b'a').unwrap(), "0");
+        assert_eq!(
+            length_limited_code_lengths(&char_map, 8),
+            Some(HashMap::from([(b'a', 1)]))
+        );
+    }
+
+    #[test]
+    fn test_canonical_codes_from_lengths() {
+        // The decoder only gets the lengths back from the header
+        let lengths = HashMap::from([(b'a', 2u8), (b'b', 1), (b'c', 3), (b'd', 3)]);
+        let codes_char = canonical_codes(&lengths);
+
+        assert_eq!(codes_char.get(&b'b').unwrap(), "0");
+        assert_eq!(codes_char.get(&b'a').unwrap(), "10");
+        assert_eq!(codes_char.get(&b'c').unwrap(), "110");
+        assert_eq!(codes_char.get(&b'd').unwrap(), "111");
+    }
 }
@@ -0,0 +1,393 @@
+// gzip (RFC 1952) files, for readers that only know that format
+//
+// header
+//   magic        2 bytes  1f 8b
+//   method       1 byte   8 = DEFLATE
+//   flags        1 byte   optional fields that follow the header
+//   mtime        4 bytes  0, the time of the original file is not kept
+//   extra flags  1 byte
+//   os           1 byte   255 = unknown
+// DEFLATE blocks
+// trailer
+//   checksum     4 bytes  CRC32 of the original bytes, little endian
+//   length       4 bytes  original size modulo 2^32, little endian
+//
+// A file can hold several of these members one after the other, they are
+// decoded as one stream, the same as gzip does.
+use std::{
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use crc32fast::Hasher;
+
+use crate::{
+    bit_io::{LsbBitReader, LsbBitWriter},
+    deflate,
+    encode::EncodeOptions,
+    error::CmError,
+    header::Coder,
+    helpers,
+};
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const DEFLATE_METHOD: u8 = 8;
+
+// Header flags
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const RESERVED: u8 = 0xe0;
+
+pub fn gzip_and_output_file(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    options: &EncodeOptions,
+    force: bool,
+) -> Result<(), CmError> {
+    let reader = helpers::open_input(input_path)?;
+    helpers::write_output(output_path, force, |writer| {
+        gzip_stream(reader, writer, options)
+    })
+}
+
+// Compresses `reader` into `writer` as a gzip file. Blocks of
+// `options.block_size` bytes become DEFLATE blocks, with LZ77 matches at
+// `options.level`. There are no code tables to reuse or give a context.
+pub fn gzip_stream<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &EncodeOptions,
+) -> Result<(), CmError> {
+    options.check()?;
+    if options.adaptive
+        || options.context
+        || options.coder != Coder::Huffman
+        || options.dictionary.is_some()
+    {
+        return Err(CmError::InvalidOptions(
+            "gzip files only hold Huffman coding, without adaptive, context or dictionary coding"
+                .to_string(),
+        ));
+    }
+    // DEFLATE fixes the longest code at 15 bits
+    if options.max_code_len.is_some() {
+        return Err(CmError::InvalidOptions(
+            "gzip files limit codes to 15 bits, there is no max code length to set".to_string(),
+        ));
+    }
+
+    let mut writer = LsbBitWriter::new(BufWriter::new(writer));
+    // extra flags 2 and 4 tell of the slowest and the fastest levels
+    let extra_flags = match options.level {
+        9 => 2,
+        1 => 4,
+        _ => 0,
+    };
+    writer.write_bytes(&[
+        MAGIC[0],
+        MAGIC[1],
+        DEFLATE_METHOD,
+        0,
+        0,
+        0,
+        0,
+        0,
+        extra_flags,
+        255,
+    ])?;
+
+    // Read a block ahead to know which one is the last
+    let mut hasher = Hasher::new();
+    let mut original_len = 0u64;
+    let mut block = read_block(&mut reader, options.block_size)?;
+    loop {
+        let next = read_block(&mut reader, options.block_size)?;
+        let last = next.is_empty();
+        hasher.update(&block);
+        original_len += block.len() as u64;
+        deflate::write_block(&mut writer, &block, options.level, last)?;
+        if last {
+            break;
+        }
+        block = next;
+    }
+
+    writer.align();
+    writer.write_bytes(&hasher.finalize().to_le_bytes())?;
+    writer.write_bytes(&(original_len as u32).to_le_bytes())?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+// Reads up to `size` bytes, fewer only at the end of the input
+fn read_block<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, CmError> {
+    let mut block: Vec<u8> = Vec::new();
+    reader.take(size as u64).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+// Decompresses every member of a gzip file from `reader` into `writer`,
+// checking each one. Returns the number of bytes written.
+pub fn gunzip_stream<R: Read, W: Write>(reader: R, mut writer: W) -> Result<u64, CmError> {
+    let mut reader = LsbBitReader::new(reader);
+    let mut total = 0u64;
+    read_header(&mut reader)?;
+    loop {
+        let mut hasher = Hasher::new();
+        let len = deflate::inflate(&mut reader, &mut writer, &mut hasher)?;
+        total += len;
+
+        reader.align();
+        let checksum = read_u32(&mut reader)?;
+        let original_len = read_u32(&mut reader)?;
+        if original_len != len as u32 {
+            return Err(CmError::format(format!(
+                "gzip file is corrupt: expected {original_len} bytes, decoded {len}"
+            )));
+        }
+        let actual = hasher.finalize();
+        if actual != checksum {
+            return Err(CmError::Checksum {
+                expected: checksum,
+                actual,
+            });
+        }
+
+        // Anything after a member that does not start another one, like the
+        // zero padding of tapes, is ignored as gzip does
+        if !next_member(&mut reader)? {
+            break;
+        }
+        read_header_fields(&mut reader)?;
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+// Checks a member header and skips its optional fields
+fn read_header<R: Read>(reader: &mut LsbBitReader<R>) -> Result<(), CmError> {
+    let magic = [read_u8(reader)?, read_u8(reader)?];
+    if magic != MAGIC {
+        return Err(CmError::format("not a gzip file"));
+    }
+    read_header_fields(reader)
+}
+
+// Whether another member follows, reading its magic number if so
+fn next_member<R: Read>(reader: &mut LsbBitReader<R>) -> Result<bool, CmError> {
+    for byte in MAGIC {
+        if reader.at_end()? || read_u8(reader)? != byte {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Same after the magic number
+fn read_header_fields<R: Read>(reader: &mut LsbBitReader<R>) -> Result<(), CmError> {
+    let method = read_u8(reader)?;
+    if method != DEFLATE_METHOD {
+        return Err(CmError::format(format!(
+            "unsupported gzip compression method {method}"
+        )));
+    }
+    let flags = read_u8(reader)?;
+    if flags & RESERVED != 0 {
+        return Err(CmError::format("gzip file is corrupt: reserved flags set"));
+    }
+    // mtime, extra flags and os
+    for _ in 0..6 {
+        read_u8(reader)?;
+    }
+
+    if flags & FEXTRA != 0 {
+        let len = read_u8(reader)? as usize | (read_u8(reader)? as usize) << 8;
+        for _ in 0..len {
+            read_u8(reader)?;
+        }
+    }
+    // zero terminated file name and comment
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            while read_u8(reader)? != 0 {}
+        }
+    }
+    if flags & FHCRC != 0 {
+        read_u8(reader)?;
+        read_u8(reader)?;
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(reader: &mut LsbBitReader<R>) -> Result<u8, CmError> {
+    Ok(reader.read_bits(8).map_err(CmError::truncated)? as u8)
+}
+
+fn read_u32<R: Read>(reader: &mut LsbBitReader<R>) -> Result<u32, CmError> {
+    Ok(reader.read_bits(32).map_err(CmError::truncated)? as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::read, io, process::Command};
+
+    fn fixture(name: &str) -> Vec<u8> {
+        read(format!(
+            "{}/tests/fixtures/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap()
+    }
+
+    fn gzip(input: &[u8], options: &EncodeOptions) -> Vec<u8> {
+        let mut encoded: Vec<u8> = Vec::new();
+        gzip_stream(input, &mut encoded, options).unwrap();
+        encoded
+    }
+
+    fn gunzip(input: &[u8]) -> Result<Vec<u8>, CmError> {
+        let mut decoded: Vec<u8> = Vec::new();
+        gunzip_stream(input, &mut decoded)?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_inflates_files_from_gzip() {
+        // made with `gzip -9` (which stores the file name) and `gzip -1 -n`
+        let text = fixture("sample.txt");
+        assert_eq!(gunzip(&fixture("sample.txt.gz")).unwrap(), text);
+        assert_eq!(gunzip(&fixture("sample-fast.txt.gz")).unwrap(), text);
+
+        // sample.bin does not compress, gzip stores it
+        assert_eq!(
+            gunzip(&fixture("sample.bin.gz")).unwrap(),
+            fixture("sample.bin")
+        );
+
+        // two members one after the other, as `cat a.gz b.gz` gives
+        let mut both = fixture("sample.txt.gz");
+        both.extend(fixture("sample.bin.gz"));
+        let mut expected = text;
+        expected.extend(fixture("sample.bin"));
+        assert_eq!(gunzip(&both).unwrap(), expected);
+
+        // padding after the last member is not a member
+        let mut padded = fixture("sample.bin.gz");
+        padded.extend([0u8; 512]);
+        assert_eq!(gunzip(&padded).unwrap(), fixture("sample.bin"));
+        let mut padded = fixture("sample.bin.gz");
+        padded.push(MAGIC[0]);
+        assert_eq!(gunzip(&padded).unwrap(), fixture("sample.bin"));
+    }
+
+    #[test]
+    fn test_round_trips_every_kind_of_block() {
+        let text = fixture("sample.txt");
+        let inputs: [&[u8]; 5] = [b"", b"a", &text, &fixture("sample.bin"), &[0u8; 100_000]];
+        for input in inputs {
+            for level in [0, 1, 6, 9] {
+                let options = EncodeOptions {
+                    block_size: 16 * 1024,
+                    level,
+                    ..EncodeOptions::default()
+                };
+                assert_eq!(gunzip(&gzip(input, &options)).unwrap(), input);
+            }
+        }
+
+        // LZ77 gets close to gzip
+        let options = EncodeOptions {
+            level: 9,
+            ..EncodeOptions::default()
+        };
+        let encoded = gzip(&text, &options);
+        assert!(encoded.len() < fixture("sample.txt.gz").len() * 21 / 20);
+    }
+
+    #[test]
+    fn test_literal_blocks_are_not_buffered_whole() {
+        // Records the size of every write
+        struct Writes(Vec<usize>);
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // level 0 has no matches, the whole input is one block of literals
+        let input = fixture("sample.txt").repeat(5);
+        let options = EncodeOptions {
+            block_size: input.len(),
+            level: 0,
+            ..EncodeOptions::default()
+        };
+        let mut writes = Writes(Vec::new());
+        gunzip_stream(gzip(&input, &options).as_slice(), &mut writes).unwrap();
+        assert_eq!(writes.0.iter().sum::<usize>(), input.len());
+        assert!(writes.0.iter().all(|len| *len < input.len() / 4));
+    }
+
+    #[test]
+    fn test_gzip_reads_the_output() {
+        // only where gzip is installed
+        let Ok(mut child) = Command::new("gzip")
+            .arg("-dc")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+        else {
+            return;
+        };
+        let options = EncodeOptions {
+            level: 6,
+            ..EncodeOptions::default()
+        };
+        let text = fixture("sample.txt");
+        let encoded = gzip(&text, &options);
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&encoded));
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, text);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_files() {
+        let encoded = fixture("sample.txt.gz");
+
+        let err = gunzip(&encoded[..encoded.len() - 4]).unwrap_err();
+        assert_eq!(err.to_string(), "archive is truncated");
+
+        let mut wrong_checksum = encoded.clone();
+        let at = wrong_checksum.len() - 8;
+        wrong_checksum[at] ^= 1;
+        assert!(matches!(
+            gunzip(&wrong_checksum).unwrap_err(),
+            CmError::Checksum { .. }
+        ));
+
+        let err = gunzip(b"CMHF not gzip").unwrap_err();
+        assert_eq!(err.to_string(), "not a gzip file");
+
+        let options = EncodeOptions {
+            adaptive: true,
+            ..EncodeOptions::default()
+        };
+        let err = gzip_stream(&b"abc"[..], io::sink(), &options).unwrap_err();
+        assert!(matches!(err, CmError::InvalidOptions(_)));
+        let options = EncodeOptions {
+            max_code_len: Some(12),
+            ..EncodeOptions::default()
+        };
+        let err = gzip_stream(&b"abc"[..], io::sink(), &options).unwrap_err();
+        assert!(matches!(err, CmError::InvalidOptions(_)));
+    }
+}
@@ -0,0 +1,832 @@
+// Binary layout of a .huff file
+//
+// header
+//   magic        4 bytes  "CMHF"
+//   version      1 byte
+//   block size   4 bytes  largest number of original bytes in a block, little endian
+//   coder        1 byte   0 = Huffman, 1 = rANS (version 3 files have none, they are Huffman)
+// blocks, each one
+//   kind         1 byte   0 = end of stream, 1 = new code table, 2 = reuse previous table,
+//                         3 = adaptive Huffman, the model carries on from the previous block,
+//                         4 = order-1 context, a code table per preceding byte,
+//                         5 = LZ77, literals and matches with DEFLATE-like alphabets,
+//                         6 = coded with the table of a dictionary (see dictionary.rs)
+//   length       4 bytes  original bytes in the block, little endian
+//   table        only for kind 1:
+//     symbols    2 bytes  number of (byte, code length) pairs, little endian
+//     pairs      2 bytes each, code lengths of 1 to 64 bits giving prefix-free codes
+//                with the rANS coder the pairs are (byte, frequency) instead, 3 bytes
+//                each with the frequency little endian, adding up to 2^15
+//   tables       only for kind 4:
+//     contexts   2 bytes  number of tables, little endian
+//     per table  the preceding byte (1 byte) then a table as for kind 1
+//   tables       only for kind 5, the literal/length table then the distance table:
+//     symbols    2 bytes  number of (symbol, code length) pairs, little endian
+//     pairs      3 bytes each, the symbol little endian
+//   dictionary   only for kind 6: ID of the dictionary, 4 bytes, little endian
+//   payload len  4 bytes  packed bytes that follow, little endian
+//   payload      the packed codes, padded to a whole byte
+// trailer, after the end of stream block
+//   length       8 bytes  original size in bytes, little endian
+//   checksum     4 bytes  CRC32 of the original bytes, little endian
+// seek index, since version 5, for every block
+//   original     8 bytes  offset of the block's first byte in the original data
+//   offset       8 bytes  start of the block from the start of the archive
+//   start        8 bytes  block to start decoding from: the block itself, the block
+//                         with the table it reuses, or the first block if adaptive
+// index footer
+//   index offset 8 bytes
+//   blocks       4 bytes  number of entries in the index
+//   magic        4 bytes  "CMHF"
+//
+// Only the code lengths are stored, the canonical codes are rebuilt from
+// them when decoding. Every block starts on a byte boundary so blocks can be
+// skipped using the payload length. In order-1 blocks the first byte is
+// coded with the table of context 0, as if a 0 byte came before it.
+// LZ77 blocks code a length symbol then its extra bits, a distance symbol
+// then its extra bits for each match, matches never reach into an earlier block.
+// rANS payloads start with the coder state, see ans.rs.
+// The seek index comes last so an archive is still written in one pass, and
+// its fixed size footer tells where to find it from the end of the file.
+// All numbers in it are little endian.
+//
+// Block kinds can be added within a version: kinds 3 to 5 came with version
+// 3 archives, kind 6 with version 5 ones. The version only changes with the
+// header, trailer or index layout, so a decoder has to treat a block kind it
+// does not know as an archive it does not support, not as a corrupt one.
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use crate::{encode::MAX_BLOCK_SIZE, error::CmError};
+
+pub const MAGIC: [u8; 4] = *b"CMHF";
+pub const VERSION: u8 = 5;
+// Last version without a seek index
+const VERSION_WITHOUT_INDEX: u8 = 4;
+// Last version without a coder byte
+const VERSION_WITHOUT_CODER: u8 = 3;
+
+// magic + version + block size + coder, for archives with a seek index
+pub const HEADER_LEN: u64 = 4 + 1 + 4 + 1;
+// index offset + blocks + magic
+pub const INDEX_FOOTER_LEN: u64 = 8 + 4 + 4;
+// original offset + offset + start
+const INDEX_ENTRY_LEN: u64 = 8 + 8 + 8;
+
+// Sizes of the LZ77 alphabets, as in DEFLATE
+pub const LITERAL_SYMBOLS: u16 = 286;
+pub const DISTANCE_SYMBOLS: u16 = 30;
+
+// Entropy coder of the blocks with a table, i.e. kinds 1 and 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coder {
+    #[default]
+    Huffman = 0,
+    // rANS with a frequency table per block
+    Ans = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub block_size: u32,
+    pub coder: Coder,
+    // Format version the archive was written with, VERSION for new ones
+    pub version: u8,
+}
+
+impl Header {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[self.version])?;
+        writer.write_all(&self.block_size.to_le_bytes())?;
+        if self.version == VERSION_WITHOUT_CODER {
+            return Ok(());
+        }
+        writer.write_all(&[self.coder as u8])
+    }
+
+    // Whether a seek index follows the trailer, archives before version 5 have none
+    pub fn seek_index(&self) -> bool {
+        self.version > VERSION_WITHOUT_INDEX
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Header, CmError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(|e| match e.kind() {
+            // Anything shorter than a header is not an archive either
+            io::ErrorKind::UnexpectedEof => CmError::format("not a cm_tool archive"),
+            _ => CmError::from(e),
+        })?;
+        if magic == crate::archive::MAGIC {
+            return Err(CmError::format(
+                "this is a multi-file archive, use unpack or list",
+            ));
+        }
+        if magic.starts_with(&crate::gzip::MAGIC) {
+            return Err(CmError::format("this is a gzip file, use decode"));
+        }
+        if magic != MAGIC {
+            return Err(CmError::format(
+                "not a cm_tool archive (files from older versions have no magic number and must be re-encoded)",
+            ));
+        }
+
+        let version = read_u8(reader)?;
+        if !(VERSION_WITHOUT_CODER..=VERSION).contains(&version) {
+            return Err(CmError::UnsupportedVersion(version));
+        }
+
+        let block_size = read_u32(reader)?;
+        // Blocks are read into memory whole, so their size is bounded
+        if block_size == 0 || block_size as usize > MAX_BLOCK_SIZE {
+            return Err(CmError::format(format!(
+                "archive is corrupt: block size {block_size} is out of range"
+            )));
+        }
+        let coder = if version == VERSION_WITHOUT_CODER {
+            Coder::Huffman
+        } else {
+            match read_u8(reader)? {
+                0 => Coder::Huffman,
+                1 => Coder::Ans,
+                coder => {
+                    return Err(CmError::format(format!(
+                        "archive is corrupt: unknown coder {coder}"
+                    )));
+                }
+            }
+        };
+        Ok(Header {
+            block_size,
+            coder,
+            version,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    End = 0,
+    NewTable = 1,
+    ReuseTable = 2,
+    Adaptive = 3,
+    Context = 4,
+    Lz77 = 5,
+    Dictionary = 6,
+}
+
+// Code lengths of every context of an order-1 block, by preceding byte
+pub type ContextLengths = Vec<(u8, HashMap<u8, u8>)>;
+
+// Code lengths of the two alphabets of an LZ77 block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LzLengths {
+    // Literal bytes, then match lengths from symbol 257 on
+    pub literals: HashMap<u16, u8>,
+    pub distances: HashMap<u16, u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub kind: BlockKind,
+    // Number of original bytes in the block
+    pub raw_len: u32,
+    // Code length of every byte in the block, only for BlockKind::NewTable
+    pub lengths: Option<HashMap<u8, u8>>,
+    // rANS frequency of every byte instead, for BlockKind::NewTable with Coder::Ans
+    pub frequencies: Option<HashMap<u8, u16>>,
+    // Code lengths for every preceding byte seen, only for BlockKind::Context
+    pub contexts: Option<ContextLengths>,
+    // Literal/length and distance code lengths, only for BlockKind::Lz77
+    pub lz: Option<LzLengths>,
+    // ID of the dictionary whose table codes the block, only for BlockKind::Dictionary
+    pub dictionary: Option<u32>,
+    // Number of packed bytes following the block header
+    pub payload_len: u32,
+}
+
+impl BlockHeader {
+    // Marks the end of the blocks, the trailer follows it
+    pub fn end() -> Self {
+        BlockHeader {
+            kind: BlockKind::End,
+            raw_len: 0,
+            lengths: None,
+            frequencies: None,
+            contexts: None,
+            lz: None,
+            dictionary: None,
+            payload_len: 0,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[self.kind as u8])?;
+        if self.kind == BlockKind::End {
+            return Ok(());
+        }
+
+        writer.write_all(&self.raw_len.to_le_bytes())?;
+        if let Some(lengths) = &self.lengths {
+            write_lengths(writer, lengths)?;
+        }
+        if let Some(frequencies) = &self.frequencies {
+            write_frequencies(writer, frequencies)?;
+        }
+        if let Some(contexts) = &self.contexts {
+            writer.write_all(&(contexts.len() as u16).to_le_bytes())?;
+            for (context, lengths) in contexts {
+                writer.write_all(&[*context])?;
+                write_lengths(writer, lengths)?;
+            }
+        }
+        if let Some(lz) = &self.lz {
+            write_symbol_lengths(writer, &lz.literals)?;
+            write_symbol_lengths(writer, &lz.distances)?;
+        }
+        if let Some(id) = self.dictionary {
+            writer.write_all(&id.to_le_bytes())?;
+        }
+        writer.write_all(&self.payload_len.to_le_bytes())
+    }
+
+    // The coder of the archive tells what the table of a new table block holds
+    pub fn read<R: Read>(reader: &mut R, coder: Coder) -> Result<BlockHeader, CmError> {
+        let kind = match read_u8(reader)? {
+            0 => return Ok(BlockHeader::end()),
+            1 => BlockKind::NewTable,
+            2 => BlockKind::ReuseTable,
+            3 => BlockKind::Adaptive,
+            4 => BlockKind::Context,
+            5 => BlockKind::Lz77,
+            6 => BlockKind::Dictionary,
+            // Kinds are added without a new version, see the top of the file
+            kind => return Err(CmError::UnsupportedBlockKind(kind)),
+        };
+
+        let raw_len = read_u32(reader)?;
+        let (lengths, frequencies) = match (kind, coder) {
+            (BlockKind::NewTable, Coder::Huffman) => (Some(read_lengths(reader)?), None),
+            (BlockKind::NewTable, Coder::Ans) => (None, Some(read_frequencies(reader)?)),
+            _ => (None, None),
+        };
+        let contexts = match kind {
+            BlockKind::Context => Some(read_contexts(reader)?),
+            _ => None,
+        };
+        let lz = match kind {
+            BlockKind::Lz77 => Some(LzLengths {
+                literals: read_symbol_lengths(reader, LITERAL_SYMBOLS)?,
+                distances: read_symbol_lengths(reader, DISTANCE_SYMBOLS)?,
+            }),
+            _ => None,
+        };
+        let dictionary = match kind {
+            BlockKind::Dictionary => Some(read_u32(reader)?),
+            _ => None,
+        };
+
+        Ok(BlockHeader {
+            kind,
+            raw_len,
+            lengths,
+            frequencies,
+            contexts,
+            lz,
+            dictionary,
+            payload_len: read_u32(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    // size and CRC32 of the original data, checked after decoding
+    pub original_len: u64,
+    pub checksum: u32,
+}
+
+impl Trailer {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.original_len.to_le_bytes())?;
+        writer.write_all(&self.checksum.to_le_bytes())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Trailer, CmError> {
+        let mut original_len = [0u8; 8];
+        reader
+            .read_exact(&mut original_len)
+            .map_err(CmError::truncated)?;
+
+        Ok(Trailer {
+            original_len: u64::from_le_bytes(original_len),
+            checksum: read_u32(reader)?,
+        })
+    }
+}
+
+// Where a block is, in the archive and in the original data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub original_offset: u64,
+    pub offset: u64,
+    // Offset of the block decoding has to start from to decode this one
+    pub start: u64,
+}
+
+// Seek index of an archive, built as the blocks are written (or read)
+#[derive(Debug, Clone, Default)]
+pub struct SeekIndex {
+    pub entries: Vec<IndexEntry>,
+    // Block with the last table sent, reused by the next ReuseTable blocks
+    table_offset: Option<u64>,
+    original_len: u64,
+}
+
+impl SeekIndex {
+    // Adds the block found at `offset` in the archive
+    pub fn push(&mut self, block: &BlockHeader, offset: u64) {
+        let start = match block.kind {
+            BlockKind::ReuseTable => self.table_offset.unwrap_or(offset),
+            // the adaptive model carries on from the very first block
+            BlockKind::Adaptive => self.entries.first().map_or(offset, |entry| entry.offset),
+            _ => offset,
+        };
+        if block.kind == BlockKind::NewTable {
+            self.table_offset = Some(offset);
+        }
+        self.entries.push(IndexEntry {
+            original_offset: self.original_len,
+            offset,
+            start,
+        });
+        self.original_len += block.raw_len as u64;
+    }
+
+    // Writes the entries then the footer, the index starts at `index_offset`
+    pub fn write<W: Write>(&self, writer: &mut W, index_offset: u64) -> io::Result<()> {
+        for entry in &self.entries {
+            writer.write_all(&entry.original_offset.to_le_bytes())?;
+            writer.write_all(&entry.offset.to_le_bytes())?;
+            writer.write_all(&entry.start.to_le_bytes())?;
+        }
+        writer.write_all(&index_offset.to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        writer.write_all(&MAGIC)
+    }
+
+    // Reads `count` entries, the footer tells how many there are
+    pub fn read<R: Read>(reader: &mut R, count: u32) -> Result<SeekIndex, CmError> {
+        let mut entries: Vec<IndexEntry> = Vec::new();
+        for _ in 0..count {
+            let mut entry = [0u8; INDEX_ENTRY_LEN as usize];
+            reader.read_exact(&mut entry).map_err(CmError::truncated)?;
+            let number =
+                |at: usize| u64::from_le_bytes(entry[at..at + 8].try_into().expect("8 bytes"));
+            entries.push(IndexEntry {
+                original_offset: number(0),
+                offset: number(8),
+                start: number(16),
+            });
+        }
+        Ok(SeekIndex {
+            entries,
+            ..SeekIndex::default()
+        })
+    }
+
+    // Returns the index offset and the number of blocks
+    pub fn read_footer<R: Read>(reader: &mut R) -> Result<(u64, u32), CmError> {
+        let mut footer = [0u8; INDEX_FOOTER_LEN as usize];
+        reader.read_exact(&mut footer).map_err(CmError::truncated)?;
+        if footer[12..] != MAGIC {
+            return Err(CmError::format("archive is corrupt: no seek index footer"));
+        }
+        Ok((
+            u64::from_le_bytes(footer[..8].try_into().expect("8 bytes")),
+            u32::from_le_bytes(footer[8..12].try_into().expect("4 bytes")),
+        ))
+    }
+
+    // Size in bytes once written, without the footer
+    pub fn size(&self) -> u64 {
+        self.entries.len() as u64 * INDEX_ENTRY_LEN
+    }
+}
+
+// Size in bytes of a code table once written
+pub fn table_size(lengths: &HashMap<u8, u8>) -> u64 {
+    2 + 2 * lengths.len() as u64
+}
+
+fn write_lengths<W: Write>(writer: &mut W, lengths: &HashMap<u8, u8>) -> io::Result<()> {
+    // Pairs are sorted so the same input always gives the same file
+    let mut pairs: Vec<(u8, u8)> = lengths.iter().map(|(b, l)| (*b, *l)).collect();
+    pairs.sort();
+    writer.write_all(&(pairs.len() as u16).to_le_bytes())?;
+    for (byte, len) in pairs {
+        writer.write_all(&[byte, len])?;
+    }
+    Ok(())
+}
+
+fn read_lengths<R: Read>(reader: &mut R) -> Result<HashMap<u8, u8>, CmError> {
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count).map_err(CmError::truncated)?;
+    let mut lengths = HashMap::new();
+    for _ in 0..u16::from_le_bytes(count) {
+        let mut pair = [0u8; 2];
+        reader.read_exact(&mut pair).map_err(CmError::truncated)?;
+        if lengths.insert(pair[0], pair[1]).is_some() {
+            return Err(CmError::format(format!(
+                "archive is corrupt: byte {} is twice in a code table",
+                pair[0]
+            )));
+        }
+    }
+    check_code_lengths(&lengths)?;
+    Ok(lengths)
+}
+
+// Code lengths must be 1 to 64 bits and give prefix-free codes, which is
+// what the decode tables are built for: the Kraft sum is at most 1
+pub fn check_code_lengths<T>(lengths: &HashMap<T, u8>) -> Result<(), CmError> {
+    let mut kraft: u128 = 0;
+    for len in lengths.values() {
+        if !(1..=64).contains(len) {
+            return Err(CmError::format(format!(
+                "archive is corrupt: code length {len} in a code table"
+            )));
+        }
+        kraft += 1u128 << (64 - len);
+    }
+    if kraft > 1u128 << 64 {
+        return Err(CmError::format(
+            "archive is corrupt: code table is not prefix-free",
+        ));
+    }
+    Ok(())
+}
+
+// Size in bytes of a frequency table once written
+pub fn frequency_table_size(frequencies: &HashMap<u8, u16>) -> u64 {
+    2 + 3 * frequencies.len() as u64
+}
+
+fn write_frequencies<W: Write>(writer: &mut W, frequencies: &HashMap<u8, u16>) -> io::Result<()> {
+    let mut pairs: Vec<(u8, u16)> = frequencies.iter().map(|(b, f)| (*b, *f)).collect();
+    pairs.sort();
+    writer.write_all(&(pairs.len() as u16).to_le_bytes())?;
+    for (byte, frequency) in pairs {
+        writer.write_all(&[byte])?;
+        writer.write_all(&frequency.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_frequencies<R: Read>(reader: &mut R) -> Result<HashMap<u8, u16>, CmError> {
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count).map_err(CmError::truncated)?;
+    let mut frequencies = HashMap::new();
+    for _ in 0..u16::from_le_bytes(count) {
+        let mut pair = [0u8; 3];
+        reader.read_exact(&mut pair).map_err(CmError::truncated)?;
+        if frequencies
+            .insert(pair[0], u16::from_le_bytes([pair[1], pair[2]]))
+            .is_some()
+        {
+            return Err(CmError::format(format!(
+                "archive is corrupt: byte {} is twice in a frequency table",
+                pair[0]
+            )));
+        }
+    }
+    Ok(frequencies)
+}
+
+// Size in bytes of a table with two byte symbols once written
+pub fn symbol_table_size(lengths: &HashMap<u16, u8>) -> u64 {
+    2 + 3 * lengths.len() as u64
+}
+
+fn write_symbol_lengths<W: Write>(writer: &mut W, lengths: &HashMap<u16, u8>) -> io::Result<()> {
+    let mut pairs: Vec<(u16, u8)> = lengths.iter().map(|(s, l)| (*s, *l)).collect();
+    pairs.sort();
+    writer.write_all(&(pairs.len() as u16).to_le_bytes())?;
+    for (symbol, len) in pairs {
+        writer.write_all(&symbol.to_le_bytes())?;
+        writer.write_all(&[len])?;
+    }
+    Ok(())
+}
+
+// Symbols must be below `alphabet_size`
+fn read_symbol_lengths<R: Read>(
+    reader: &mut R,
+    alphabet_size: u16,
+) -> Result<HashMap<u16, u8>, CmError> {
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count).map_err(CmError::truncated)?;
+    let mut lengths = HashMap::new();
+    for _ in 0..u16::from_le_bytes(count) {
+        let mut pair = [0u8; 3];
+        reader.read_exact(&mut pair).map_err(CmError::truncated)?;
+        let symbol = u16::from_le_bytes([pair[0], pair[1]]);
+        if symbol >= alphabet_size {
+            return Err(CmError::format(format!(
+                "archive is corrupt: unknown symbol {symbol} in a code table"
+            )));
+        }
+        if lengths.insert(symbol, pair[2]).is_some() {
+            return Err(CmError::format(format!(
+                "archive is corrupt: symbol {symbol} is twice in a code table"
+            )));
+        }
+    }
+    check_code_lengths(&lengths)?;
+    Ok(lengths)
+}
+
+fn read_contexts<R: Read>(reader: &mut R) -> Result<ContextLengths, CmError> {
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count).map_err(CmError::truncated)?;
+    let count = u16::from_le_bytes(count);
+    if count > 256 {
+        return Err(CmError::format(format!(
+            "archive is corrupt: {count} context tables"
+        )));
+    }
+    (0..count)
+        .map(|_| Ok((read_u8(reader)?, read_lengths(reader)?)))
+        .collect()
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, CmError> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf).map_err(CmError::truncated)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, CmError> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).map_err(CmError::truncated)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header {
+            block_size: 1 << 20,
+            coder: Coder::Ans,
+            version: VERSION,
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        header.write(&mut buf).unwrap();
+
+        // magic + version + block size + coder
+        assert_eq!(buf.len() as u64, HEADER_LEN);
+        assert_eq!(&buf[..4], b"CMHF");
+        assert_eq!(Header::read(&mut buf.as_slice()).unwrap(), header);
+
+        // a cut off header is an error, not a panic
+        let err = Header::read(&mut &buf[..buf.len() - 1]).unwrap_err();
+        assert_eq!(err.to_string(), "archive is truncated");
+    }
+
+    #[test]
+    fn test_block_header_round_trip() {
+        let blocks = [
+            BlockHeader {
+                kind: BlockKind::NewTable,
+                raw_len: 30,
+                lengths: Some(HashMap::from([(b'a', 1), (b'b', 2), (b'c', 2)])),
+                frequencies: None,
+                contexts: None,
+                lz: None,
+                dictionary: None,
+                payload_len: 6,
+            },
+            BlockHeader {
+                kind: BlockKind::ReuseTable,
+                raw_len: 12,
+                lengths: None,
+                frequencies: None,
+                contexts: None,
+                lz: None,
+                dictionary: None,
+                payload_len: 3,
+            },
+            BlockHeader::end(),
+            BlockHeader {
+                kind: BlockKind::Context,
+                raw_len: 4,
+                lengths: None,
+                frequencies: None,
+                contexts: Some(vec![
+                    (0, HashMap::from([(b'a', 1)])),
+                    (b'a', HashMap::from([(b'a', 1), (b'b', 1)])),
+                ]),
+                lz: None,
+                dictionary: None,
+                payload_len: 1,
+            },
+            BlockHeader {
+                kind: BlockKind::Lz77,
+                raw_len: 9,
+                lengths: None,
+                frequencies: None,
+                contexts: None,
+                lz: Some(LzLengths {
+                    literals: HashMap::from([(b'a' as u16, 1), (257, 1)]),
+                    distances: HashMap::from([(2, 1)]),
+                }),
+                dictionary: None,
+                payload_len: 1,
+            },
+            BlockHeader {
+                kind: BlockKind::Dictionary,
+                raw_len: 5,
+                dictionary: Some(0x1234_5678),
+                payload_len: 2,
+                ..BlockHeader::end()
+            },
+        ];
+
+        let mut buf: Vec<u8> = Vec::new();
+        for block in &blocks {
+            block.write(&mut buf).unwrap();
+        }
+
+        // kind + length + (count + 3 pairs) + payload length, then without a table, then the end,
+        // then 2 contexts with 1 and 2 pairs, then 2 literal/length and 1 distance pairs,
+        // then a dictionary ID
+        assert_eq!(
+            buf.len(),
+            (1 + 4 + 2 + 6 + 4)
+                + (1 + 4 + 4)
+                + 1
+                + (1 + 4 + 2 + (1 + 2 + 2) + (1 + 2 + 4) + 4)
+                + (1 + 4 + (2 + 6) + (2 + 3) + 4)
+                + (1 + 4 + 4 + 4)
+        );
+
+        let mut reader = buf.as_slice();
+        for block in &blocks {
+            assert_eq!(
+                &BlockHeader::read(&mut reader, Coder::Huffman).unwrap(),
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn test_frequency_table_round_trip() {
+        let block = BlockHeader {
+            kind: BlockKind::NewTable,
+            raw_len: 30,
+            lengths: None,
+            frequencies: Some(HashMap::from([(b'a', 30000), (b'b', 2768)])),
+            contexts: None,
+            lz: None,
+            dictionary: None,
+            payload_len: 6,
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        block.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), 1 + 4 + (2 + 6) + 4);
+        assert_eq!(
+            BlockHeader::read(&mut buf.as_slice(), Coder::Ans).unwrap(),
+            block
+        );
+    }
+
+    #[test]
+    fn test_rejects_symbols_outside_the_alphabet() {
+        // distance symbol 30 does not exist
+        let mut buf: Vec<u8> = vec![5, 3, 0, 0, 0, 0, 0, 1, 0];
+        buf.extend_from_slice(&[30, 0, 1]);
+        let err = BlockHeader::read(&mut buf.as_slice(), Coder::Huffman).unwrap_err();
+        assert!(err.to_string().contains("unknown symbol 30"));
+    }
+
+    #[test]
+    fn test_trailer_round_trip() {
+        let trailer = Trailer {
+            original_len: 30,
+            checksum: 0xdead_beef,
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        trailer.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), 8 + 4);
+        assert_eq!(Trailer::read(&mut buf.as_slice()).unwrap(), trailer);
+    }
+
+    #[test]
+    fn test_rejects_old_message_pack_files() {
+        // The old format started with a MessagePack array
+        let old: &[u8] = &[0x93, 0x81, 0x61, 0xa1, 0x30];
+        let err = Header::read(&mut &old[..]).unwrap_err();
+        assert!(matches!(err, CmError::Format(_)));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        for version in [VERSION_WITHOUT_CODER - 1, VERSION + 1] {
+            let mut buf = MAGIC.to_vec();
+            buf.extend_from_slice(&[version, 0, 0, 0, 0, 0]);
+            let err = Header::read(&mut buf.as_slice()).unwrap_err();
+            assert!(matches!(err, CmError::UnsupportedVersion(v) if v == version));
+            assert!(err.to_string().contains("unsupported archive version"));
+        }
+    }
+
+    #[test]
+    fn test_reads_version_3_headers() {
+        // no coder byte, all Huffman
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[3, 0, 1, 0, 0]);
+        let header = Header::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(header.block_size, 256);
+        assert_eq!(header.coder, Coder::Huffman);
+        assert_eq!(header.version, 3);
+        assert!(!header.seek_index());
+
+        // and written back the same
+        let mut written: Vec<u8> = Vec::new();
+        header.write(&mut written).unwrap();
+        assert_eq!(written, buf);
+    }
+
+    #[test]
+    fn test_seek_index_round_trip() {
+        let block = |kind, raw_len| BlockHeader {
+            kind,
+            raw_len,
+            ..BlockHeader::end()
+        };
+        let mut index = SeekIndex::default();
+        index.push(&block(BlockKind::NewTable, 100), 10);
+        index.push(&block(BlockKind::ReuseTable, 100), 60);
+        index.push(&block(BlockKind::Lz77, 100), 90);
+        index.push(&block(BlockKind::ReuseTable, 30), 150);
+        let starts: Vec<(u64, u64)> = index
+            .entries
+            .iter()
+            .map(|entry| (entry.original_offset, entry.start))
+            .collect();
+        // LZ77 blocks bring their own tables, the last block still reuses the first one's
+        assert_eq!(starts, [(0, 10), (100, 10), (200, 90), (300, 10)]);
+
+        let mut buf: Vec<u8> = Vec::new();
+        index.write(&mut buf, 200).unwrap();
+        assert_eq!(buf.len() as u64, index.size() + INDEX_FOOTER_LEN);
+        let (entries, footer) = buf.split_at(index.size() as usize);
+        assert_eq!(SeekIndex::read_footer(&mut &footer[..]).unwrap(), (200, 4));
+        let read = SeekIndex::read(&mut &entries[..], 4).unwrap();
+        assert_eq!(read.entries, index.entries);
+    }
+
+    #[test]
+    fn test_rejects_unknown_block_kind() {
+        let err = BlockHeader::read(&mut [9u8].as_slice(), Coder::Huffman).unwrap_err();
+        assert!(matches!(err, CmError::UnsupportedBlockKind(9)));
+        assert_eq!(err.exit_code(), 7);
+    }
+
+    #[test]
+    fn test_rejects_bad_code_tables() {
+        // a new table block with 3 (byte, length) pairs
+        let table = |pairs: [u8; 6]| {
+            let mut buf: Vec<u8> = vec![1, 3, 0, 0, 0, 3, 0];
+            buf.extend_from_slice(&pairs);
+            buf.extend_from_slice(&[0, 0, 0, 0]);
+            BlockHeader::read(&mut buf.as_slice(), Coder::Huffman)
+        };
+        assert!(table([b'a', 1, b'b', 2, b'c', 2]).is_ok());
+        for (pairs, message) in [
+            ([b'a', 1, b'b', 1, b'c', 1], "not prefix-free"),
+            ([b'a', 1, b'b', 0, b'c', 2], "code length 0"),
+            ([b'a', 1, b'b', 65, b'c', 2], "code length 65"),
+            ([b'a', 1, b'b', 2, b'a', 2], "byte 97 is twice"),
+        ] {
+            let err = table(pairs).unwrap_err();
+            assert!(err.to_string().contains(message), "{err}");
+        }
+
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[VERSION, 0xff, 0xff, 0xff, 0xff, 0]);
+        let err = Header::read(&mut buf.as_slice()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("block size 4294967295 is out of range")
+        );
+    }
+}
@@ -1,92 +1,269 @@
 use std::{
     collections::HashMap,
-    fs::{File, read, write},
-    io::{BufRead, BufReader, Write},
-    path::{Path, PathBuf},
+    fs::File,
+    hash::Hash,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
 };
 
-use bitvec::prelude::*;
+use crc32fast::Hasher;
+use tempfile::NamedTempFile;
 
-use crate::EncodedData;
+use crate::{bit_io::BitWriter, error::CmError};
 
-pub fn load_file<P: AsRef<Path>>(path: P) -> BufReader<File> {
-    // Open file in path
-    let file = File::open(path).expect("Error, file not found");
-    // create a new buffer reader
-    BufReader::new(file)
+// "-" stands for stdin as an input and stdout as an output
+pub fn is_stdio<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new("-")
 }
 
-pub fn read_file<P: AsRef<Path>>(input_path: P) -> Vec<u8> {
-    let file_content = read(input_path).expect("Unable to read");
-    file_content
-} 
+// Opens a file or stdin for reading
+pub fn open_input<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    if is_stdio(&path) {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        let file = File::open(&path).map_err(|e| with_path(e, &path))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// Gives `write` a buffered writer to the output file, or stdout.
+//
+// A file is written under a temporary name in the same directory and only
+// renamed to `path` once `write` succeeded, so a failure never leaves a
+// partial file behind. An existing file is only replaced when `force` is set.
+pub fn write_output<P: AsRef<Path>>(
+    path: P,
+    force: bool,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), CmError>,
+) -> Result<(), CmError> {
+    let path = path.as_ref();
+    if is_stdio(path) {
+        let mut writer = BufWriter::new(io::stdout().lock());
+        write(&mut writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    // Refuse before doing any work, the rename checks again at the end
+    if !force && path.exists() {
+        return Err(already_exists(path).into());
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let temp = temp_file_in(dir).map_err(|e| with_path(e, path))?;
+    let mut writer = BufWriter::new(temp);
+    write(&mut writer)?;
+    let temp = writer.into_inner().map_err(|e| e.into_error())?;
+    temp.as_file().sync_all()?;
 
-pub fn get_char_occurence(reader: BufReader<File>) -> HashMap<char, u64> {
-    // char hashMap
-    let mut char_map: HashMap<char, u64> = HashMap::new();
-    // Read the file line by line
-    for line in reader.lines() {
-        let line = line.expect("Could not read line");
-        // save the chars into an HashMap
-        for ch in line.chars() {
-            // Store char in hashmap
-            *char_map.entry(ch).or_insert(0) += 1;
+    let persisted = if force {
+        temp.persist(path)
+    } else {
+        temp.persist_noclobber(path)
+    };
+    match persisted {
+        Ok(_) => Ok(()),
+        Err(e) if e.error.kind() == io::ErrorKind::AlreadyExists => {
+            Err(already_exists(path).into())
         }
+        Err(e) => Err(with_path(e.error, path).into()),
     }
-    char_map
 }
 
-pub fn write_header_to_file<P: AsRef<Path>>(output: P, codes: &HashMap<char, String>) {
-    // Create the file
-    // Create a header section
-    // Demarcate the header and the body with a new line with Text
-    // Write the char and code in the header section
-    let mut file = File::create(output).expect("Error Creating file");
+// Temp files are only readable by their owner, give the output the usual
+// permissions instead
+#[cfg(unix)]
+fn temp_file_in(dir: &Path) -> io::Result<NamedTempFile> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+    tempfile::Builder::new()
+        .prefix(".cm_tool")
+        .permissions(Permissions::from_mode(0o666))
+        .tempfile_in(dir)
+}
+
+#[cfg(not(unix))]
+fn temp_file_in(dir: &Path) -> io::Result<NamedTempFile> {
+    tempfile::Builder::new().prefix(".cm_tool").tempfile_in(dir)
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} already exists (use --force to overwrite)",
+            path.display()
+        ),
+    )
+}
+
+// Names the file in the error message, keeping the kind of error
+pub fn with_path<P: AsRef<Path>>(e: io::Error, path: P) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {e}", path.as_ref().display()))
+}
 
-    for (ch, code) in codes {
-        // Write the code to the file
-        write!(file, "{}:{}\n", ch, code).expect("Error writing to file");
+pub fn get_char_occurence<R: Read>(mut reader: R) -> io::Result<HashMap<u8, u64>> {
+    // byte hashMap
+    let mut char_map: HashMap<u8, u64> = HashMap::new();
+    // Read the raw bytes, so newlines and non UTF-8 data are counted too
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if read == 0 {
+            break;
+        }
+        // Store each byte in hashmap
+        for byte in &buf[..read] {
+            *char_map.entry(*byte).or_insert(0) += 1;
+        }
     }
+    Ok(char_map)
+}
 
-    write!(file, "encoding\n").expect("Error writing to file");
+// Reader that keeps the CRC32 and length of everything read through it
+pub struct ChecksumReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+    len: u64,
 }
 
-pub fn encoder(reader: BufReader<File>, codes: &HashMap<char, String>, output_path: &PathBuf) {
-    // let mut writer = BufWriter::new(file);
-    // create a bitvec holder
-    let mut bv: BitVec<u8, Msb0> = BitVec::<u8, Msb0>::new();
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(),
+            len: 0,
+        }
+    }
 
-    for line in reader.lines() {
-        let line = line.expect("failed to read line");
-        push_to_bit(&mut bv, line, codes);
+    // Returns (length, CRC32) of the bytes read so far
+    pub fn finish(self) -> (u64, u32) {
+        (self.len, self.hasher.finalize())
     }
+}
 
-    // let packed_bytes = bv.as_raw_slice();
-    // write to file
-    let encoded_data = EncodedData {
-        codes: codes.clone(),
-        total_bits: bv.len(),
-        data: bv.as_raw_slice().to_vec()
-    };
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+// Writer that counts the bytes going through it
+pub struct CountingWriter<W: Write> {
+    pub inner: W,
+    pub count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
 
-    let buf = rmp_serde::to_vec(&encoded_data).expect("Error converting to message packed bytes");
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Same for the bytes read through it
+pub struct CountingReader<R: Read> {
+    pub inner: R,
+    pub count: u64,
+}
 
-    write(output_path, buf).expect("Unable to write file");
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+// Number of bits the encoded data will take, known before encoding from the counts
+pub fn total_bits<T: Eq + Hash>(char_map: &HashMap<T, u64>, codes: &HashMap<T, String>) -> u64 {
+    char_map
+        .iter()
+        .map(|(sym, count)| count * codes.get(sym).map_or(0, |code| code.len() as u64))
+        .sum()
 }
 
-// Converts each lines to bits
-pub fn push_to_bit(bv: &mut BitVec<u8, Msb0>, line: String, codes: &HashMap<char, String>) {
-    for ch in line.chars() {
-        if let Some(bits) = codes.get(&ch) {
-            for bit in bits.chars() {
-                if bit == '1' {
-                    bv.push(true);
-                } else {
-                    bv.push(false)
-                }
-            }
+// Shannon entropy of the byte counts, in bits per byte
+pub fn entropy(char_map: &HashMap<u8, u64>) -> f64 {
+    let total: u64 = char_map.values().sum();
+    char_map
+        .values()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum::<f64>()
+        // no data or a single byte value sums to -0.0
+        .abs()
+}
+
+// Turns the '0'/'1' code strings into (bits, length) pairs indexed by
+// symbol, with room for at least every byte
+pub fn pack_codes<T: Copy + Into<usize>>(codes: &HashMap<T, String>) -> Vec<(u64, u8)> {
+    let size = codes.keys().map(|sym| (*sym).into() + 1).max().unwrap_or(0);
+    let mut packed = vec![(0u64, 0u8); size.max(256)];
+    for (sym, code) in codes {
+        assert!(code.len() <= 64, "Huffman code too long");
+        let bits = code
+            .chars()
+            .fold(0u64, |acc, bit| (acc << 1) | (bit == '1') as u64);
+        packed[(*sym).into()] = (bits, code.len() as u8);
+    }
+    packed
+}
+
+pub fn encoder<R: Read, W: Write>(
+    mut reader: R,
+    codes: &HashMap<u8, String>,
+    writer: W,
+) -> io::Result<()> {
+    // Bytes are written out as soon as a chunk of them is packed
+    let codes = pack_codes(codes);
+    let mut bit_writer = BitWriter::new(writer);
+
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if read == 0 {
+            break;
         }
+        push_to_bit(&mut bit_writer, &buf[..read], &codes)?;
     }
+
+    bit_writer.finish()?;
+    Ok(())
+}
+
+// Converts each chunk of bytes to bits
+pub fn push_to_bit<W: Write>(
+    bit_writer: &mut BitWriter<W>,
+    bytes: &[u8],
+    codes: &[(u64, u8)],
+) -> io::Result<()> {
+    for byte in bytes {
+        let (bits, len) = codes[*byte as usize];
+        bit_writer.write_bits(bits, len)?;
+    }
+    Ok(())
 }
 
 // Invert the code map
@@ -123,38 +300,80 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_write_to_file() {
-        let mut char_map: HashMap<char, String> = HashMap::new();
-        char_map.insert('a', "00".to_string());
+    fn test_char_occurence_counts_every_byte() {
+        let input: &[u8] = b"ab\nb\r\n\xff";
+        let char_map = get_char_occurence(input).unwrap();
+
+        assert_eq!(char_map.get(&b'a'), Some(&1));
+        assert_eq!(char_map.get(&b'b'), Some(&2));
+        assert_eq!(char_map.get(&b'\n'), Some(&2));
+        assert_eq!(char_map.get(&b'\r'), Some(&1));
+        assert_eq!(char_map.get(&0xff), Some(&1));
+    }
+
+    #[test]
+    fn test_write_output_leaves_nothing_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
 
-        write_header_to_file("test_output.txt", &char_map);
+        let err = write_output(&path, false, |writer| {
+            writer.write_all(b"half of the output")?;
+            Err(CmError::format("archive is corrupt"))
+        })
+        .unwrap_err();
+        assert!(matches!(err, CmError::Format(_)));
+        // neither the output nor the temp file is there
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
 
-        // read the file from the disk and assert contents
-        let file = File::open("test_output.txt").expect("Error Opening file");
-        let buf_reader = BufReader::new(file);
-        let mut lines = buf_reader.lines();
+        write_output(&path, false, |writer| Ok(writer.write_all(b"done")?)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"done");
+    }
+
+    #[test]
+    fn test_entropy() {
+        // 4 equally likely bytes take 2 bits each
+        let uniform = get_char_occurence(&b"abcdabcd"[..]).unwrap();
+        assert!((entropy(&uniform) - 2.0).abs() < 1e-9);
+
+        // a single repeated byte carries no information
+        let single = get_char_occurence(&b"aaaa"[..]).unwrap();
+        assert_eq!(entropy(&single), 0.0);
+        assert!(entropy(&single).is_sign_positive());
+        assert!(entropy(&HashMap::new()).is_sign_positive());
+    }
+
+    #[test]
+    fn test_checksum_reader() {
+        let input: &[u8] = b"The quick brown fox jumps over the lazy dog";
+        let mut reader = ChecksumReader::new(input);
+        get_char_occurence(&mut reader).unwrap();
 
-        assert_eq!(lines.next().unwrap().unwrap(), "a -> 00");
+        // well known CRC32 of the sentence
+        assert_eq!(reader.finish(), (43, 0x414f_a339));
     }
 
     #[test]
     fn test_push_to_bits() {
-        let line = String::from("abcaba");
+        let bytes = b"abcaba";
         let codes = HashMap::from([
-            ('a', "0".to_string()),
-            ('b', "10".to_string()),
-            ('c', "11".to_string()),
+            (b'a', "0".to_string()),
+            (b'b', "10".to_string()),
+            (b'c', "11".to_string()),
         ]);
 
-        let mut bv = BitVec::<u8, Msb0>::new();
-        push_to_bit(&mut bv, line, &codes);
-        let packed_bytes = bv.as_raw_slice();
+        let mut bit_writer = BitWriter::new(Vec::new());
+        push_to_bit(&mut bit_writer, bytes, &pack_codes(&codes)).unwrap();
+        let packed_bytes = bit_writer.finish().unwrap();
 
         // abcaba into bits - 01011010 00000000
         // 01011010 binary - 90 (decimals)
         // 00000000 binary - 0 (decimals)
         assert_eq!(packed_bytes[0], 90);
         assert_eq!(packed_bytes[1], 0);
+        assert_eq!(
+            total_bits(&get_char_occurence(&bytes[..]).unwrap(), &codes),
+            9
+        );
     }
 
     // #[test]
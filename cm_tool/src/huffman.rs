@@ -87,20 +87,28 @@ impl<T: Clone + Eq> PartialOrd for Tree<T> {
     }
 }
 
-pub fn huffman_tree<T: Clone + Eq + Debug>(char_map: HashMap<T, u64>) -> Tree<T> {
+// Returns None when there are no chars, there is no tree to build then
+pub fn huffman_tree<T: Clone + Ord + Debug>(char_map: HashMap<T, u64>) -> Option<Tree<T>> {
     // Since Binary Heap is Max Heap by default, use reverse for min Heap
     let mut min_heap = BinaryHeap::new();
 
+    // Sort the chars so equal frequencies always merge in the same order,
+    // the HashMap iteration order is random
+    let mut chars: Vec<(T, u64)> = char_map.into_iter().collect();
+    chars.sort();
+
     // Add char and frequencies to the heap table
-    for (char, freq) in char_map {
-        let (char, freq) = (char.clone(), freq);
-        min_heap.push(Reverse(Leaf { char, freq }));
+    // The sequence number breaks ties between equal frequencies (first pushed, first popped)
+    let mut seq: u64 = 0;
+    for (char, freq) in chars {
+        min_heap.push(Reverse((Leaf { char, freq }, seq)));
+        seq += 1;
     }
 
     while min_heap.len() > 1 {
         // get the first two smallest elements
-        let node1 = min_heap.pop().unwrap().0;
-        let node2 = min_heap.pop().unwrap().0;
+        let node1 = min_heap.pop().unwrap().0.0;
+        let node2 = min_heap.pop().unwrap().0.0;
 
         // merge the two nodes
         let merged_node = Node {
@@ -110,10 +118,11 @@ pub fn huffman_tree<T: Clone + Eq + Debug>(char_map: HashMap<T, u64>) -> Tree<T>
         };
 
         // push the merged node into the max_heap
-        min_heap.push(Reverse(merged_node));
+        min_heap.push(Reverse((merged_node, seq)));
+        seq += 1;
     }
     // Return the Tree
-    min_heap.pop().unwrap().0
+    min_heap.pop().map(|entry| entry.0.0)
 }
 
 #[cfg(test)]
@@ -129,7 +138,7 @@ mod tests {
         char_map.insert('c', 15);
 
         // Build a huffman tree
-        let tree = huffman_tree(char_map);
+        let tree = huffman_tree(char_map).unwrap();
         assert_eq!(tree.freq(), 90); // confirming that the final node gives a freq of 90
         assert_eq!(tree.left().map(|n| n.freq()), Some(40));
         assert_eq!(tree.right().map(|n| n.freq()), Some(50));
@@ -178,4 +187,13 @@ mod tests {
             Some(10)
         );
     }
+    #[test]
+    fn test_empty_and_single_char() {
+        assert_eq!(huffman_tree::<u8>(HashMap::new()), None);
+
+        // a lone char is the whole tree
+        let tree = huffman_tree(HashMap::from([(b'a', 7)])).unwrap();
+        assert_eq!(tree.char(), Some(b'a'));
+        assert_eq!(tree.freq(), 7);
+    }
 }
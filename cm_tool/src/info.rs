@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::{
+    archive::{Entry, EntryKind},
+    decode::decode_stream_with_dictionary,
+    dictionary::Dictionary,
+    error::CmError,
+    generate_huffman_codes::canonical_codes,
+    header::Coder,
+    helpers::{self, CountingReader},
+};
+
+// Writer that only counts the bytes written to it
+struct CountingSink {
+    counts: [u64; 256],
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.counts[*byte as usize] += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Prints the symbol table and compression statistics of an archive.
+// The archive is decoded (without writing anything) to get the byte counts.
+pub fn print_info(
+    input_path: &PathBuf,
+    dictionary: Option<Arc<Dictionary>>,
+) -> Result<(), CmError> {
+    let reader = helpers::open_input(input_path)?;
+    let name = input_path.display().to_string();
+    write_info(reader, &mut io::stdout().lock(), &name, dictionary)
+}
+
+// Same, from any reader into `out`
+pub fn write_info<R: Read, W: Write>(
+    reader: R,
+    out: &mut W,
+    name: &str,
+    dictionary: Option<Arc<Dictionary>>,
+) -> Result<(), CmError> {
+    // Count the archive bytes as they are read, the input may be stdin
+    let mut reader = CountingReader {
+        inner: reader,
+        count: 0,
+    };
+    let mut sink = CountingSink { counts: [0; 256] };
+    let summary = decode_stream_with_dictionary(&mut reader, &mut sink, dictionary.clone())?;
+    let compressed_size = reader.count;
+    let original_len = summary.trailer.original_len;
+
+    let char_map: HashMap<u8, u64> = (0..=255u8)
+        .map(|byte| (byte, sink.counts[byte as usize]))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+
+    writeln!(out, "Archive:          {name}")?;
+    writeln!(out, "Format version:   {}", summary.header.version)?;
+    writeln!(out, "Block size:       {} bytes", summary.header.block_size)?;
+    writeln!(
+        out,
+        "Coder:            {}",
+        match summary.header.coder {
+            Coder::Huffman => "Huffman",
+            Coder::Ans => "rANS",
+        }
+    )?;
+    if summary.adaptive_blocks > 0 {
+        writeln!(
+            out,
+            "Blocks:           {} (adaptive Huffman, no code tables)",
+            summary.blocks
+        )?;
+    } else if summary.lz_blocks > 0 || summary.context_blocks > 0 {
+        writeln!(
+            out,
+            "Blocks:           {} ({} code tables, {} with order-1 context tables, {} with LZ77 matches)",
+            summary.blocks, summary.tables, summary.context_blocks, summary.lz_blocks
+        )?;
+    } else {
+        writeln!(
+            out,
+            "Blocks:           {} ({} code tables)",
+            summary.blocks, summary.tables
+        )?;
+    }
+    if summary.dictionary_blocks > 0
+        && let Some(dictionary) = &dictionary
+    {
+        writeln!(
+            out,
+            "Dictionary:       {:08x} ({} blocks use its table)",
+            dictionary.id, summary.dictionary_blocks
+        )?;
+    }
+    writeln!(
+        out,
+        "Seek index:       {}",
+        if summary.header.seek_index() {
+            "yes"
+        } else {
+            "no, extract needs an archive made by this version"
+        }
+    )?;
+    writeln!(out, "Original size:    {original_len} bytes")?;
+    writeln!(out, "Compressed size:  {compressed_size} bytes")?;
+    if original_len > 0 {
+        writeln!(
+            out,
+            "Ratio:            {:.2}% of original",
+            compressed_size as f64 / original_len as f64 * 100.0
+        )?;
+        writeln!(
+            out,
+            "Bits per symbol:  {:.4}",
+            compressed_size as f64 * 8.0 / original_len as f64
+        )?;
+    }
+    writeln!(
+        out,
+        "Entropy:          {:.4} bits per symbol",
+        helpers::entropy(&char_map)
+    )?;
+    writeln!(
+        out,
+        "Checksum:         {:08x} (CRC32)",
+        summary.trailer.checksum
+    )?;
+    writeln!(out, "Symbols:          {}", char_map.len())?;
+
+    let Some(lengths) = summary.lengths else {
+        return Ok(());
+    };
+    writeln!(out)?;
+    if summary.tables > 1 {
+        writeln!(out, "Codes of the first of {} tables", summary.tables)?;
+    }
+
+    // Symbol table, in canonical order
+    let codes = canonical_codes(&lengths);
+    let mut symbols: Vec<(u8, u8)> = lengths.iter().map(|(b, l)| (*l, *b)).collect();
+    symbols.sort();
+    writeln!(out, "{:<8} {:>12} {:>6}  Code", "Symbol", "Count", "Length")?;
+    for (len, byte) in symbols {
+        writeln!(
+            out,
+            "{:<8} {:>12} {:>6}  {}",
+            display_byte(byte),
+            char_map.get(&byte).unwrap_or(&0),
+            len,
+            codes[&byte]
+        )?;
+    }
+    Ok(())
+}
+
+// Prints the entries of a multi-file archive, one per line like `ls -l`
+pub fn print_entries(entries: &[Entry]) {
+    let (mut size, mut compressed_size) = (0u64, 0u64);
+    for entry in entries {
+        println!(
+            "{} {:>12} {:>12}  {}  {}{}",
+            display_mode(entry),
+            entry.size,
+            entry.compressed_len,
+            display_time(entry.mtime),
+            entry.path,
+            if entry.kind == EntryKind::Directory {
+                "/"
+            } else {
+                ""
+            }
+        );
+        size += entry.size;
+        compressed_size += entry.compressed_len;
+    }
+    println!(
+        "{} entries, {size} bytes, {compressed_size} compressed",
+        entries.len()
+    );
+}
+
+// Permissions as ls shows them, e.g. drwxr-xr-x
+fn display_mode(entry: &Entry) -> String {
+    let kind = match entry.kind {
+        EntryKind::File => '-',
+        EntryKind::Directory => 'd',
+    };
+    let bits = (0..9).rev().map(|bit| {
+        if entry.mode & (1 << bit) == 0 {
+            '-'
+        } else {
+            ['x', 'w', 'r'][bit % 3]
+        }
+    });
+    std::iter::once(kind).chain(bits).collect()
+}
+
+// Seconds since the Unix epoch as a UTC date and time
+fn display_time(mtime: i64) -> String {
+    let (days, seconds) = (mtime.div_euclid(86_400), mtime.rem_euclid(86_400));
+
+    // Days since 1970-01-01 to a civil date, from Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+// Printable ASCII is shown as is, anything else as hex
+fn display_byte(byte: u8) -> String {
+    match byte {
+        b' ' => "' '".to_string(),
+        b'!'..=b'~' => (byte as char).to_string(),
+        b'\n' => "\\n".to_string(),
+        b'\r' => "\\r".to_string(),
+        b'\t' => "\\t".to_string(),
+        _ => format!("0x{byte:02x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_byte() {
+        assert_eq!(display_byte(b'a'), "a");
+        assert_eq!(display_byte(b' '), "' '");
+        assert_eq!(display_byte(b'\n'), "\\n");
+        assert_eq!(display_byte(0xe9), "0xe9");
+    }
+
+    #[test]
+    fn test_display_entry() {
+        let entry = Entry {
+            kind: EntryKind::Directory,
+            path: "logs".to_string(),
+            size: 0,
+            mode: 0o750,
+            mtime: 0,
+            offset: 5,
+            compressed_len: 0,
+        };
+        assert_eq!(display_mode(&entry), "drwxr-x---");
+        assert_eq!(display_time(0), "1970-01-01 00:00");
+        assert_eq!(display_time(951_827_696), "2000-02-29 12:34");
+        assert_eq!(display_time(-60), "1969-12-31 23:59");
+    }
+
+    #[test]
+    fn test_info_on_a_64_bit_code() {
+        // version 4 archive of "a", whose only code is 64 zero bits
+        let mut archive: Vec<u8> = b"CMHF\x04".to_vec();
+        archive.extend_from_slice(&256u32.to_le_bytes());
+        archive.push(0);
+        archive.extend_from_slice(&[1, 1, 0, 0, 0, 1, 0, b'a', 64]);
+        archive.extend_from_slice(&8u32.to_le_bytes());
+        archive.extend_from_slice(&[0; 8]);
+        archive.push(0);
+        archive.extend_from_slice(&1u64.to_le_bytes());
+        archive.extend_from_slice(&crc32fast::hash(b"a").to_le_bytes());
+
+        let mut out: Vec<u8> = Vec::new();
+        write_info(archive.as_slice(), &mut out, "a.huff", None).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Format version:   4"));
+        assert!(out.contains(&format!("a                   1     64  {}", "0".repeat(64))));
+    }
+}
@@ -0,0 +1,213 @@
+//! Huffman compression engine behind the `cm_tool` command line tool.
+//!
+//! Data is split into blocks, each coded with its own canonical Huffman table
+//! (or the previous block's one), and the archive ends with the size and
+//! CRC32 of the original data, which are checked when decompressing.
+//!
+//! ```
+//! let compressed = cm_tool::compress(b"abracadabra").unwrap();
+//! assert_eq!(cm_tool::decompress(&compressed).unwrap(), b"abracadabra");
+//! ```
+//!
+//! [`HuffmanEncoder`] and [`HuffmanDecoder`] do the same on streams,
+//! [`SeekableDecoder`] reads any range of an archive file without decoding
+//! all of it, and [`gzip_stream`] and [`gunzip_stream`] read and write gzip files.
+use std::io::{Read, Write};
+
+mod adaptive;
+mod ans;
+mod archive;
+mod bench;
+mod bit_io;
+mod decode;
+mod decode_table;
+mod deflate;
+mod dictionary;
+mod encode;
+mod error;
+mod generate_huffman_codes;
+mod gzip;
+mod header;
+mod helpers;
+mod huffman;
+mod info;
+mod lz77;
+mod seekable;
+
+pub use archive::{ArchiveReader, ArchiveWriter, Entry, EntryKind, list_archive, pack, unpack};
+pub use bench::{BenchResult, bench, print_results as print_bench, results_json as bench_json};
+pub use decode::{
+    HuffmanDecoder, Summary, decode_and_output_file, decode_stream, decode_stream_with_dictionary,
+};
+pub use dictionary::{Dictionary, train};
+pub use encode::{
+    DEFAULT_BLOCK_SIZE, EncodeOptions, HuffmanEncoder, MAX_BLOCK_SIZE, MAX_THREADS,
+    encode_and_output_file, encode_stream,
+};
+pub use error::CmError;
+pub use gzip::{gunzip_stream, gzip_and_output_file, gzip_stream};
+pub use header::{Coder, Header, Trailer};
+pub use helpers::{is_stdio, open_input};
+pub use info::{print_entries, print_info, write_info};
+pub use seekable::{SeekableDecoder, extract_range};
+
+/// Compresses `data` with the default options.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, CmError> {
+    compress_with(data, &EncodeOptions::default())
+}
+
+/// Compresses `data` with the given block size, threads and coding options.
+pub fn compress_with(data: &[u8], options: &EncodeOptions) -> Result<Vec<u8>, CmError> {
+    let mut encoder = HuffmanEncoder::with_options(Vec::new(), options.clone());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses a whole archive, checking its size and checksum.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, CmError> {
+    let mut decoded: Vec<u8> = Vec::new();
+    HuffmanDecoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let input: Vec<u8> = b"in memory buffers, no files involved\r\n".repeat(100);
+        let compressed = compress(&input).unwrap();
+        assert!(compressed.len() < input.len());
+        assert_eq!(decompress(&compressed).unwrap(), input);
+
+        let options = EncodeOptions {
+            block_size: 1000,
+            adaptive: true,
+            ..EncodeOptions::default()
+        };
+        let compressed = compress_with(&input, &options).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), input);
+    }
+
+    #[test]
+    fn test_decompress_rejects_garbage() {
+        let err = decompress(b"not an archive").unwrap_err();
+        assert!(matches!(err, CmError::Format(_)));
+
+        // cut short, with the error kept through the Read adapter
+        let compressed = compress(b"abracadabra").unwrap();
+        let err = decompress(&compressed[..compressed.len() - 3]).unwrap_err();
+        assert_eq!(err.to_string(), "archive is truncated");
+    }
+
+    #[test]
+    fn test_compress_rejects_invalid_options() {
+        let options = EncodeOptions {
+            block_size: 0,
+            ..EncodeOptions::default()
+        };
+        let err = compress_with(b"abc", &options).unwrap_err();
+        assert!(matches!(err, CmError::InvalidOptions(_)));
+    }
+
+    #[test]
+    fn test_line_endings_are_kept() {
+        let inputs: [&[u8]; 7] = [
+            b"first line\nsecond line\n",
+            b"no newline at the end",
+            b"windows\r\nline endings\r\n",
+            b"mixed\r\nendings\nand a lone\rreturn",
+            b"\n",
+            b"\r\n\r\n\r\n",
+            b"\n\ntrailing blank lines\n\n\n",
+        ];
+        for input in inputs {
+            assert_eq!(decompress(&compress(input).unwrap()).unwrap(), input);
+        }
+    }
+
+    // Any options that pass EncodeOptions::check
+    fn options() -> impl Strategy<Value = EncodeOptions> {
+        (
+            1usize..600,
+            1usize..4,
+            any::<bool>(),
+            prop::option::of(8u8..=64),
+            any::<bool>(),
+            0u8..=9,
+            any::<bool>(),
+        )
+            .prop_map(
+                |(block_size, threads, adaptive, max_code_len, context, level, ans)| {
+                    // the ans coder only goes with the default table options
+                    if ans && !adaptive {
+                        return EncodeOptions {
+                            block_size,
+                            threads,
+                            coder: Coder::Ans,
+                            ..EncodeOptions::default()
+                        };
+                    }
+                    EncodeOptions {
+                        block_size,
+                        threads,
+                        adaptive,
+                        max_code_len,
+                        context: context && !adaptive,
+                        level: if adaptive { 0 } else { level },
+                        coder: Coder::Huffman,
+                        dictionary: None,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_bytes_round_trip(input in prop::collection::vec(any::<u8>(), 0..4096), options in options()) {
+            let compressed = compress_with(&input, &options).unwrap();
+            prop_assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+
+        // Hostile archives: every decoder must return an error, not panic or
+        // allocate more than a block
+        #[test]
+        fn prop_corrupt_archives_are_rejected(
+            input in prop::collection::vec(any::<u8>(), 0..2048),
+            options in options(),
+            flips in prop::collection::vec((any::<prop::sample::Index>(), 1u8..=255), 1..4),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let compressed = compress_with(&input, &options).unwrap();
+            let mut corrupt = compressed.clone();
+            for (at, mask) in flips {
+                corrupt[at.index(compressed.len())] ^= mask;
+            }
+            let _ = decompress(&corrupt);
+            let _ = decompress(&compressed[..cut.index(compressed.len())]);
+            let mut decoded: Vec<u8> = Vec::new();
+            if let Ok(mut decoder) = SeekableDecoder::new(std::io::Cursor::new(&corrupt)) {
+                let _ = decoder.read_to_end(&mut decoded);
+            }
+        }
+
+        #[test]
+        fn prop_garbage_is_rejected(tail in prop::collection::vec(any::<u8>(), 0..512)) {
+            // a valid header so the garbage reaches the block parser
+            let mut garbage = compress(b"").unwrap()[..header::HEADER_LEN as usize].to_vec();
+            garbage.extend_from_slice(&tail);
+            prop_assert!(decompress(&garbage).is_err());
+            prop_assert!(gunzip_stream(&[&gzip::MAGIC[..], &tail].concat()[..], std::io::sink()).is_err());
+            prop_assert!(ArchiveReader::new(std::io::Cursor::new(&tail)).is_err());
+            prop_assert!(Dictionary::read(&mut tail.as_slice()).is_err());
+        }
+
+        #[test]
+        fn prop_text_round_trip(input in "[a-c \t\r\n]{0,2000}", options in options()) {
+            let compressed = compress_with(input.as_bytes(), &options).unwrap();
+            prop_assert_eq!(decompress(&compressed).unwrap(), input.as_bytes());
+        }
+    }
+}
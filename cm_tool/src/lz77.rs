@@ -0,0 +1,288 @@
+// LZ77 match finding for the DEFLATE-like blocks
+//
+// A block is turned into literal bytes and (length, distance) matches that
+// copy bytes seen earlier in the same block, so blocks stay independent of
+// each other. Lengths and distances are coded with the DEFLATE alphabets:
+// a symbol picks a range of values and extra bits give the value in it.
+//
+// Matches are found with hash chains over the last WINDOW_SIZE bytes, the
+// level sets how far down the chains to look and whether to try a longer
+// match one byte further on before taking one (lazy matching).
+
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+pub const WINDOW_SIZE: usize = 32 * 1024;
+pub const MAX_LEVEL: u8 = 9;
+
+// Literal/length alphabet: 0-255 are literal bytes, 256 is DEFLATE's end of
+// block (not used, the block length is known) and 257-285 are lengths
+pub const FIRST_LENGTH_SYMBOL: u16 = 257;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const HASH_BITS: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    // Copy `length` bytes starting `distance` bytes back
+    Match { length: u16, distance: u16 },
+}
+
+// Symbol, extra bits value and number of extra bits of a match length
+pub fn length_symbol(length: u16) -> (u16, u16, u8) {
+    let index = LENGTH_BASE.partition_point(|base| *base <= length) - 1;
+    (
+        FIRST_LENGTH_SYMBOL + index as u16,
+        length - LENGTH_BASE[index],
+        LENGTH_EXTRA[index],
+    )
+}
+
+// Same for a match distance
+pub fn distance_symbol(distance: u16) -> (u16, u16, u8) {
+    let index = DISTANCE_BASE.partition_point(|base| *base <= distance) - 1;
+    (
+        index as u16,
+        distance - DISTANCE_BASE[index],
+        DISTANCE_EXTRA[index],
+    )
+}
+
+// Smallest length of a length symbol and its number of extra bits,
+// None for literals and symbols outside the alphabet
+pub fn length_base(symbol: u16) -> Option<(u16, u8)> {
+    let index = symbol.checked_sub(FIRST_LENGTH_SYMBOL)? as usize;
+    Some((*LENGTH_BASE.get(index)?, LENGTH_EXTRA[index]))
+}
+
+// Same for a distance symbol
+pub fn distance_base(symbol: u16) -> Option<(u16, u8)> {
+    let index = symbol as usize;
+    Some((*DISTANCE_BASE.get(index)?, DISTANCE_EXTRA[index]))
+}
+
+// How hard a level looks for matches
+struct Params {
+    // Most candidates tried for a match
+    max_chain: usize,
+    // A match this long is taken without looking any further
+    nice_length: usize,
+    lazy: bool,
+}
+
+fn params(level: u8) -> Params {
+    let (max_chain, nice_length, lazy) = match level {
+        0 | 1 => (4, 8, false),
+        2 => (8, 16, false),
+        3 => (16, 32, false),
+        4 => (16, 16, true),
+        5 => (32, 32, true),
+        6 => (128, 128, true),
+        7 => (256, 128, true),
+        8 => (1024, MAX_MATCH, true),
+        _ => (4096, MAX_MATCH, true),
+    };
+    Params {
+        max_chain,
+        nice_length,
+        lazy,
+    }
+}
+
+// Hash chains: the last position of each 3 byte hash, and for every
+// position in the window the previous one with the same hash.
+// Positions are stored plus one, 0 ends a chain.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        MatchFinder {
+            data,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.data[pos..pos + MIN_MATCH];
+        let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = pos as u32 + 1;
+    }
+
+    // Longest match for the bytes at `pos` as (length, distance)
+    fn longest(&self, pos: usize, params: &Params) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > self.data.len() {
+            return None;
+        }
+        let max_len = MAX_MATCH.min(self.data.len() - pos);
+        let mut best: Option<(usize, usize)> = None;
+        let mut best_len = MIN_MATCH - 1;
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = params.max_chain;
+
+        while candidate != 0 && chain > 0 {
+            let start = candidate as usize - 1;
+            if pos - start > WINDOW_SIZE {
+                break;
+            }
+            // Only compare the whole match if it could beat the best one
+            if self.data[start + best_len] == self.data[pos + best_len] {
+                let len = self.data[start..start + max_len]
+                    .iter()
+                    .zip(&self.data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best = Some((len, pos - start));
+                    if len >= params.nice_length || len == max_len {
+                        break;
+                    }
+                }
+            }
+
+            // Chains only go back in the block, anything else is a stale slot
+            let next = self.prev[start % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        best
+    }
+}
+
+// Splits `data` into literals and matches, harder looking with higher levels
+pub fn find_matches(data: &[u8], level: u8) -> Vec<Token> {
+    let params = params(level);
+    let mut finder = MatchFinder::new(data);
+    let mut tokens: Vec<Token> = Vec::new();
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let found = finder.longest(pos, &params);
+        finder.insert(pos);
+
+        // Emit a literal instead if the next byte starts a longer match
+        if let Some((length, _)) = found
+            && params.lazy
+            && length < params.nice_length
+            && finder
+                .longest(pos + 1, &params)
+                .is_some_and(|(next_length, _)| next_length > length)
+        {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+            continue;
+        }
+
+        match found {
+            Some((length, distance)) => {
+                tokens.push(Token::Match {
+                    length: length as u16,
+                    distance: distance as u16,
+                });
+                for skipped in pos + 1..pos + length {
+                    finder.insert(skipped);
+                }
+                pos += length;
+            }
+            None => {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rebuilds the data the way the decoder does
+    fn expand(tokens: &[Token]) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::new();
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => data.push(byte),
+                Token::Match { length, distance } => {
+                    for _ in 0..length {
+                        data.push(data[data.len() - distance as usize]);
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_matches_expand_back() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let input = &text[..100_000];
+        for level in 1..=MAX_LEVEL {
+            let tokens = find_matches(input, level);
+            assert_eq!(expand(&tokens), input, "level {level}");
+            assert!(tokens.len() < input.len() / 2, "level {level}");
+        }
+
+        // overlapping matches, as for a run of one byte
+        let run = vec![b'a'; 1000];
+        let tokens = find_matches(&run, 6);
+        assert_eq!(tokens[0], Token::Literal(b'a'));
+        assert!(matches!(tokens[1], Token::Match { distance: 1, .. }));
+        assert_eq!(expand(&tokens), run);
+    }
+
+    #[test]
+    fn test_symbols_round_trip() {
+        for length in MIN_MATCH as u16..=MAX_MATCH as u16 {
+            let (symbol, extra, extra_bits) = length_symbol(length);
+            assert!((FIRST_LENGTH_SYMBOL..=285).contains(&symbol));
+            assert!(extra < 1 << extra_bits);
+            let (base, bits) = length_base(symbol).unwrap();
+            assert_eq!((base + extra, bits), (length, extra_bits));
+        }
+        assert_eq!(length_symbol(258).0, 285);
+
+        for distance in 1..=WINDOW_SIZE as u16 {
+            let (symbol, extra, extra_bits) = distance_symbol(distance);
+            assert!(symbol < 30);
+            let (base, bits) = distance_base(symbol).unwrap();
+            assert_eq!((base + extra, bits), (distance, extra_bits));
+        }
+
+        assert_eq!(length_base(b'a' as u16), None);
+        assert_eq!(length_base(286), None);
+        assert_eq!(distance_base(30), None);
+    }
+}
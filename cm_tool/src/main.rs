@@ -1,62 +1,359 @@
-use clap::{Parser, ValueEnum};
-use serde::{Deserialize, Serialize};
-use std::{ collections::HashMap, path::PathBuf, time};
-
-mod decode;
-mod encode;
-mod generate_huffman_codes;
-mod helpers;
-mod huffman;
+use clap::{CommandFactory, Parser, ValueEnum, error::ErrorKind};
+use cm_tool::{
+    CmError, Coder, DEFAULT_BLOCK_SIZE, Dictionary, EncodeOptions, MAX_BLOCK_SIZE, MAX_THREADS,
+    bench, bench_json, decode_and_output_file, decode_stream_with_dictionary,
+    encode_and_output_file, extract_range, gzip_and_output_file, is_stdio, list_archive,
+    open_input, pack, print_bench, print_entries, print_info, train, unpack,
+};
+use std::{io, ops::Range, path::PathBuf, process, sync::Arc, time};
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_about=None)]
 pub struct Args {
     #[arg(value_enum)]
     action: Action,
-    #[arg(help = "input file path")]
+    #[arg(help = "input file path, - for stdin (the directory to pack for pack)")]
     input: PathBuf,
-    #[arg(help = "new output file path")]
-    output: PathBuf,
+    #[arg(
+        help = "new output file path, - for stdout (encode, decode, pack, extract and train), or the directory to unpack to"
+    )]
+    output: Option<PathBuf>,
+    #[arg(help = "more files to benchmark (bench)")]
+    files: Vec<PathBuf>,
+    /// Same as the output argument
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "PATH",
+        conflicts_with = "output"
+    )]
+    output_flag: Option<PathBuf>,
+    /// Format written by encode, decode recognises either
+    #[arg(long, value_enum, default_value_t = Format::Huff)]
+    format: Format,
+    /// Dictionary made by train: encode uses its tables where they save space, decoding needs the same one
+    #[arg(long = "dict", value_name = "PATH")]
+    dictionary: Option<PathBuf>,
+    /// Entropy coder: huffman, or ans (rANS, better on very skewed data)
+    #[arg(long, default_value = "huffman", value_parser = parse_coder)]
+    coder: Coder,
+    /// Number of bytes encoded with each code table
+    #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE, value_parser = parse_block_size)]
+    block_size: usize,
+    /// Number of threads encoding blocks at the same time
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=MAX_THREADS as i64))]
+    threads: u16,
+    /// One pass adaptive Huffman coding, no code tables are stored (ignores --threads)
+    #[arg(long)]
+    adaptive: bool,
+    /// Order-1 context modelling: a code table per preceding byte, used for the blocks it shrinks
+    #[arg(long, conflicts_with = "adaptive")]
+    context: bool,
+    /// LZ77 match finding before Huffman coding, 1 (fastest) to 9 (smallest), 0 for none
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=9), conflicts_with = "adaptive")]
+    level: u8,
+    /// Longest code allowed, longer codes are replaced by length limited ones
+    #[arg(long, value_parser = clap::value_parser!(u8).range(8..=64))]
+    max_code_len: Option<u8>,
+    /// Overwrite the output file if it already exists
+    #[arg(long, short)]
+    force: bool,
+    /// Only unpack this entry of a multi-file archive, can be repeated
+    #[arg(long = "entry", value_name = "PATH")]
+    entries: Vec<String>,
+    /// Bytes of the original data to extract, START..END with either end left out
+    #[arg(long, value_parser = parse_range, required_if_eq("action", "extract"))]
+    range: Option<Range<u64>>,
+    /// Print the bench results as JSON
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Action {
     Encode,
     Decode,
+    /// Print the symbol table and compression statistics of an archive
+    Info,
+    /// Decode an archive without writing it out and check its integrity
+    Test,
+    /// Pack a directory into a multi-file archive
+    Pack,
+    /// Extract a multi-file archive into a directory (the current one by default)
+    Unpack,
+    /// List the entries of a multi-file archive
+    List,
+    /// Decode only the --range of the original data, from the blocks covering it
+    Extract,
+    /// Build a dictionary from sample files, for archives of small similar files
+    Train,
+    /// Compress and decompress files in memory with every mode and report sizes and speeds
+    Bench,
 }
 
-
-#[derive(Serialize, Deserialize)]
-pub struct EncodedData {
-    // Huffman code map
-    codes: HashMap<char, String>,
-    // exact number of bits
-    total_bits: usize,
-    // Packed Binary bytes
-    data: Vec<u8>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// cm_tool's own .huff archive
+    Huff,
+    /// gzip file any gzip reader can decompress (no --adaptive, --context or --max-code-len)
+    Gzip,
 }
 
 // Sample commands
 // cargo run -- encode test.txt outHuff.huff
+// cargo run -- encode app.log app.huff --level 6
+// cargo run -- encode app.log app.log.gz --format gzip --level 6
+// cargo run -- encode sparse.log sparse.huff --coder ans
 // cargo run -- decode outHuff.huff testNew.txt
+// cargo run -- info outHuff.huff
+// cargo run -- test outHuff.huff
+// tar c dir | cargo run -- encode - - > dir.huff
+// cargo run -- pack logs/ logs.huff
+// cargo run -- list logs.huff
+// cargo run -- unpack logs.huff restored/ --entry app/today.log
+// cargo run -- extract app.huff - --range 1000000..1004096 | grep ERROR
+// cargo run -- train samples/ -o messages.huffdict
+// cargo run -- encode message.json message.huff --dict messages.huffdict
+// cargo run -- bench test.txt app.log sample.bin --json > bench.json
 
 fn main() {
     let args = Args::parse();
+    if args.action != Action::Bench && !args.files.is_empty() {
+        Args::command()
+            .error(
+                ErrorKind::TooManyValues,
+                "only bench takes more than an input and an output file",
+            )
+            .exit();
+    }
+    let dictionary = load_dictionary(&args);
 
     match args.action {
         Action::Encode => {
+            let output = output_path(&args);
             // Start encoding while it's timed
+            let options = encode_options(&args, dictionary);
             let timer = time::Instant::now();
-            encode::encode_and_output_file(&args.output, &args.input);
+            let result = match args.format {
+                Format::Huff => encode_and_output_file(&output, &args.input, &options, args.force),
+                Format::Gzip => gzip_and_output_file(&output, &args.input, &options, args.force),
+            };
+            if let Err(e) = result {
+                exit_with_error(&args, e);
+            }
             let time = timer.elapsed();
-            println!("File encoded in {time:?}")
+            report(&output, format!("File encoded in {time:?}"));
         }
         Action::Decode => {
+            let output = output_path(&args);
             // Start decoding
             let timer = time::Instant::now();
-            decode::decode_and_output_file(&args.output, &args.input);
+            if let Err(e) = decode_and_output_file(&output, &args.input, dictionary, args.force) {
+                exit_with_error(&args, e);
+            }
             let time = timer.elapsed();
-            println!("File decoded in {time:?}");
+            report(&output, format!("File decoded in {time:?}"));
+        }
+        Action::Info => {
+            if let Err(e) = print_info(&args.input, dictionary) {
+                exit_with_error(&args, e);
+            }
+        }
+        Action::Test => {
+            // Decode into nothing, only the integrity checks matter
+            let reader = match open_input(&args.input) {
+                Ok(reader) => reader,
+                Err(e) => exit_with_error(&args, e.into()),
+            };
+            match decode_stream_with_dictionary(reader, io::sink(), dictionary) {
+                Ok(summary) => println!(
+                    "{}: OK ({} bytes in {} blocks, CRC32 {:08x})",
+                    args.input.display(),
+                    summary.trailer.original_len,
+                    summary.blocks,
+                    summary.trailer.checksum
+                ),
+                Err(e) => exit_with_error(&args, e),
+            }
         }
+        Action::Pack => {
+            if args.format == Format::Gzip {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "multi-file archives hold .huff streams, --format gzip only works with encode",
+                    )
+                    .exit();
+            }
+            let output = output_path(&args);
+            let timer = time::Instant::now();
+            match pack(
+                &output,
+                &args.input,
+                &encode_options(&args, dictionary),
+                args.force,
+            ) {
+                Ok(entries) => {
+                    let time = timer.elapsed();
+                    report(
+                        &output,
+                        format!("{} entries packed in {time:?}", entries.len()),
+                    );
+                }
+                Err(e) => exit_with_error(&args, e),
+            }
+        }
+        Action::Unpack => {
+            let output = output_arg(&args).unwrap_or_else(|| PathBuf::from("."));
+            let timer = time::Instant::now();
+            match unpack(&output, &args.input, &args.entries, args.force) {
+                Ok(entries) => {
+                    let time = timer.elapsed();
+                    println!("{} entries unpacked in {time:?}", entries.len());
+                }
+                Err(e) => exit_with_error(&args, e),
+            }
+        }
+        Action::List => match list_archive(&args.input) {
+            Ok(entries) => print_entries(&entries),
+            Err(e) => exit_with_error(&args, e),
+        },
+        Action::Extract => {
+            let output = output_path(&args);
+            let range = args
+                .range
+                .clone()
+                .expect("clap requires --range for extract");
+            let timer = time::Instant::now();
+            match extract_range(&output, &args.input, range, dictionary, args.force) {
+                Ok(len) => {
+                    let time = timer.elapsed();
+                    report(&output, format!("{len} bytes extracted in {time:?}"));
+                }
+                Err(e) => exit_with_error(&args, e),
+            }
+        }
+        Action::Train => {
+            let output = output_path(&args);
+            match train(&output, &args.input, args.force) {
+                Ok((dictionary, samples)) => report(
+                    &output,
+                    format!(
+                        "Dictionary {:08x} trained on {samples} files",
+                        dictionary.id
+                    ),
+                ),
+                Err(e) => exit_with_error(&args, e),
+            }
+        }
+        Action::Bench => {
+            // Every argument is an input here
+            let mut files = vec![args.input.clone()];
+            files.extend(args.output.clone());
+            files.extend(args.files.iter().cloned());
+            match bench(&files, &encode_options(&args, None)) {
+                Ok(results) if args.json => println!("{}", bench_json(&results)),
+                Ok(results) => print_bench(&results),
+                Err(e) => exit_with_error(&args, e),
+            }
+        }
+    }
+}
+
+fn load_dictionary(args: &Args) -> Option<Arc<Dictionary>> {
+    let path = args.dictionary.as_ref()?;
+    match Dictionary::load(path) {
+        Ok(dictionary) => Some(Arc::new(dictionary)),
+        Err(e) => {
+            eprintln!("Error reading {}: {e}", path.display());
+            process::exit(e.exit_code());
+        }
+    }
+}
+
+fn encode_options(args: &Args, dictionary: Option<Arc<Dictionary>>) -> EncodeOptions {
+    EncodeOptions {
+        block_size: args.block_size,
+        threads: args.threads as usize,
+        adaptive: args.adaptive,
+        max_code_len: args.max_code_len,
+        context: args.context,
+        level: args.level,
+        coder: args.coder,
+        dictionary,
+    }
+}
+
+fn parse_coder(value: &str) -> Result<Coder, String> {
+    match value {
+        "huffman" => Ok(Coder::Huffman),
+        "ans" => Ok(Coder::Ans),
+        _ => Err("the coder is huffman or ans".to_string()),
+    }
+}
+
+fn parse_block_size(value: &str) -> Result<usize, String> {
+    let size: usize = value.parse().map_err(|e| format!("{e}"))?;
+    if size == 0 || size > MAX_BLOCK_SIZE {
+        return Err(format!(
+            "block size must be between 1 and {} bytes",
+            MAX_BLOCK_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+// START..END, START.. or ..END
+fn parse_range(value: &str) -> Result<Range<u64>, String> {
+    let Some((start, end)) = value.split_once("..") else {
+        return Err("the range is START..END".to_string());
+    };
+    let parse = |bound: &str, default: u64| match bound {
+        "" => Ok(default),
+        _ => bound.parse::<u64>().map_err(|e| format!("{e}")),
+    };
+    let range = parse(start, 0)?..parse(end, u64::MAX)?;
+    if range.start > range.end {
+        return Err("the range ends before it starts".to_string());
+    }
+    Ok(range)
+}
+
+// The output argument, or -o
+fn output_arg(args: &Args) -> Option<PathBuf> {
+    args.output.clone().or_else(|| args.output_flag.clone())
+}
+
+// Encode, decode, pack, extract and train need an output path, the other actions do not
+fn output_path(args: &Args) -> PathBuf {
+    match output_arg(args) {
+        Some(output) => output,
+        None => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "an output file path is required to encode, decode, pack, extract or train",
+            )
+            .exit(),
+    }
+}
+
+// Status messages go to stderr when stdout carries the data
+fn report(output: &PathBuf, message: String) {
+    if is_stdio(output) {
+        eprintln!("{message}");
+    } else {
+        println!("{message}");
+    }
+}
+
+// Exit codes, so scripts can tell failures apart:
+// 1 I/O error, 2 invalid arguments, 3 file not found, 4 permission denied,
+// 5 not an archive or archive corrupt, 6 checksum mismatch, 7 unsupported archive version or block kind
+fn exit_with_error(args: &Args, e: CmError) -> ! {
+    match &e {
+        // I/O errors name the file they are about
+        CmError::Io(_) => eprintln!("Error: {e}"),
+        _ => eprintln!("Error reading {}: {e}", args.input.display()),
     }
+    process::exit(e.exit_code());
 }
@@ -0,0 +1,387 @@
+// Random access to the original bytes of a .huff archive, through the seek
+// index at its end (see header.rs)
+//
+// Only the blocks covering what is read get decoded. A block is decoded
+// from the block its index entry starts at: the block itself, the block
+// with the table it reuses (whose payload is skipped) or, for adaptive
+// archives, the first block. Reading on from one block to the next carries
+// on where the previous block ended, without seeking back.
+//
+// The CRC32 in the trailer covers all of the original data, so what is read
+// this way is not checked against it, only the structure of the blocks is.
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::{
+    decode::{BlockState, check_block_sizes, read_payload},
+    dictionary::Dictionary,
+    error::CmError,
+    header::{
+        BlockHeader, BlockKind, HEADER_LEN, Header, INDEX_FOOTER_LEN, IndexEntry, SeekIndex,
+        Trailer,
+    },
+    helpers,
+};
+
+// original length + checksum
+const TRAILER_LEN: u64 = 8 + 4;
+
+pub struct SeekableDecoder<R: Read + Seek> {
+    reader: BufReader<R>,
+    header: Header,
+    trailer: Trailer,
+    entries: Vec<IndexEntry>,
+    state: BlockState,
+    // Block the reader is at, with `state` as decoding the previous ones left it
+    next: Option<usize>,
+    // Block held in `decoded`
+    current: Option<usize>,
+    payload: Vec<u8>,
+    decoded: Vec<u8>,
+    // Position in the original data
+    position: u64,
+}
+
+impl<R: Read + Seek> SeekableDecoder<R> {
+    // Reads the header, trailer and seek index of the archive in `reader`
+    pub fn new(reader: R) -> Result<Self, CmError> {
+        Self::with_dictionary(reader, None)
+    }
+
+    // Same for archives whose blocks may use the tables of `dictionary`
+    pub fn with_dictionary(
+        reader: R,
+        dictionary: Option<Arc<Dictionary>>,
+    ) -> Result<Self, CmError> {
+        let mut reader = BufReader::new(reader);
+        reader.seek(SeekFrom::Start(0))?;
+        let header = Header::read(&mut reader)?;
+        if !header.seek_index() {
+            return Err(CmError::format(
+                "archive has no seek index, it was made by an older version",
+            ));
+        }
+
+        // The footer says where the index is, the trailer comes right before it
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+        if archive_len < HEADER_LEN + 1 + TRAILER_LEN + INDEX_FOOTER_LEN {
+            return Err(CmError::format("archive is truncated"));
+        }
+        reader.seek(SeekFrom::Start(archive_len - INDEX_FOOTER_LEN))?;
+        let (index_offset, count) = SeekIndex::read_footer(&mut reader)?;
+        if index_offset < HEADER_LEN + 1 + TRAILER_LEN || index_offset > archive_len {
+            return Err(CmError::format("archive is corrupt: bad index offset"));
+        }
+        reader.seek(SeekFrom::Start(index_offset - TRAILER_LEN))?;
+        let trailer = Trailer::read(&mut reader)?;
+        let index = SeekIndex::read(&mut reader, count)?;
+        if index_offset + index.size() + INDEX_FOOTER_LEN != archive_len {
+            return Err(CmError::format("archive is corrupt: bad seek index"));
+        }
+
+        // The end of stream block comes right before the trailer
+        let end_offset = index_offset - TRAILER_LEN - 1;
+        check_entries(&index.entries, end_offset, trailer.original_len)?;
+
+        Ok(SeekableDecoder {
+            reader,
+            header,
+            trailer,
+            entries: index.entries,
+            state: BlockState::new(dictionary),
+            next: None,
+            current: None,
+            payload: Vec::new(),
+            decoded: Vec::new(),
+            position: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // Size of the original data
+    pub fn original_len(&self) -> u64 {
+        self.trailer.original_len
+    }
+
+    // Decodes block `target` into `decoded`
+    fn load(&mut self, target: usize) -> Result<(), CmError> {
+        let entry = &self.entries[target];
+        // Carry on from the block the reader is at, unless starting over
+        // from the block the target depends on skips more
+        let from = match self.next {
+            Some(next) if next <= target && entry.start <= self.entries[next].offset => next,
+            _ => {
+                let from = self
+                    .entries
+                    .binary_search_by_key(&entry.start, |entry| entry.offset)
+                    .map_err(|_| CmError::format("archive is corrupt: bad seek index"))?;
+                self.reader.seek(SeekFrom::Start(entry.start))?;
+                self.state.reset();
+                from
+            }
+        };
+
+        self.next = None;
+        self.current = None;
+        for i in from..=target {
+            let block = BlockHeader::read(&mut self.reader, self.header.coder)?;
+            let raw_len = self.block_end(i) - self.entries[i].original_offset;
+            if block.kind == BlockKind::End || block.raw_len as u64 != raw_len {
+                return Err(CmError::format(
+                    "archive is corrupt: seek index does not match the blocks",
+                ));
+            }
+            check_block_sizes(&self.header, &block)?;
+            self.state.take_table(&block)?;
+
+            // Blocks before the target only matter for their tables, but
+            // the adaptive model has to see every byte
+            if i < target && block.kind != BlockKind::Adaptive {
+                self.reader.seek_relative(block.payload_len as i64)?;
+                continue;
+            }
+            read_payload(&mut self.reader, &block, &mut self.payload)?;
+            self.decoded.clear();
+            self.state
+                .decode(self.header.coder, &block, &self.payload, &mut self.decoded)?;
+        }
+        self.next = Some(target + 1);
+        self.current = Some(target);
+        Ok(())
+    }
+
+    // Original offset where block `i` ends
+    fn block_end(&self, i: usize) -> u64 {
+        self.entries
+            .get(i + 1)
+            .map_or(self.trailer.original_len, |entry| entry.original_offset)
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.trailer.original_len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Last block starting at or before the position, empty blocks are skipped
+        let target = self
+            .entries
+            .partition_point(|entry| entry.original_offset <= self.position)
+            - 1;
+        if self.current != Some(target) {
+            self.load(target)?;
+        }
+
+        let at = (self.position - self.entries[target].original_offset) as usize;
+        let len = buf.len().min(self.decoded.len() - at);
+        buf[..len].copy_from_slice(&self.decoded[at..at + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableDecoder<R> {
+    // Positions are in the original data, past its end reads return nothing
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.trailer.original_len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot seek before the start of the data",
+            ));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+// The entries must cover the original data in order, with every block
+// between the header and the end of stream block, after the one it starts from
+fn check_entries(
+    entries: &[IndexEntry],
+    end_offset: u64,
+    original_len: u64,
+) -> Result<(), CmError> {
+    let mut previous: Option<&IndexEntry> = None;
+    for entry in entries {
+        let in_order = match previous {
+            Some(previous) => {
+                entry.offset > previous.offset && entry.original_offset >= previous.original_offset
+            }
+            None => entry.original_offset == 0,
+        };
+        if !in_order
+            || entry.start < HEADER_LEN
+            || entry.start > entry.offset
+            || entry.offset >= end_offset
+            || entry.original_offset > original_len
+        {
+            return Err(CmError::format("archive is corrupt: bad seek index"));
+        }
+        previous = Some(entry);
+    }
+    if entries.is_empty() && original_len > 0 {
+        return Err(CmError::format("archive is corrupt: bad seek index"));
+    }
+    Ok(())
+}
+
+// Decodes the bytes of `range` from the archive at `input_path` into
+// `output_path`, only decoding the blocks covering them. The range is cut
+// at the end of the data. Returns the number of bytes extracted.
+pub fn extract_range(
+    output_path: &PathBuf,
+    input_path: &PathBuf,
+    range: Range<u64>,
+    dictionary: Option<Arc<Dictionary>>,
+    force: bool,
+) -> Result<u64, CmError> {
+    if helpers::is_stdio(input_path) {
+        return Err(CmError::InvalidOptions(
+            "extract needs an archive file it can seek in, not stdin".to_string(),
+        ));
+    }
+    let file = File::open(input_path).map_err(|e| helpers::with_path(e, input_path))?;
+    let mut decoder = SeekableDecoder::with_dictionary(file, dictionary)?;
+
+    let end = range.end.min(decoder.original_len());
+    let start = range.start.min(end);
+    decoder.seek(SeekFrom::Start(start))?;
+    helpers::write_output(output_path, force, |writer| {
+        io::copy(&mut (&mut decoder).take(end - start), writer)?;
+        Ok(())
+    })?;
+    Ok(end - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{EncodeOptions, encode_stream};
+    use std::io::Cursor;
+
+    fn encode(input: &[u8], options: &EncodeOptions) -> Vec<u8> {
+        let mut encoded: Vec<u8> = Vec::new();
+        encode_stream(input, &mut encoded, options).unwrap();
+        encoded
+    }
+
+    // Reads `range` through a SeekableDecoder
+    fn read_range(encoded: &[u8], range: Range<usize>) -> Vec<u8> {
+        let mut decoder = SeekableDecoder::new(Cursor::new(encoded)).unwrap();
+        decoder.seek(SeekFrom::Start(range.start as u64)).unwrap();
+        let mut read: Vec<u8> = Vec::new();
+        decoder
+            .take((range.end - range.start) as u64)
+            .read_to_end(&mut read)
+            .unwrap();
+        read
+    }
+
+    #[test]
+    fn test_reads_any_range() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.txt")).unwrap();
+        let input = &text[..200_000];
+        let option_sets = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                adaptive: true,
+                ..EncodeOptions::default()
+            },
+            EncodeOptions {
+                level: 6,
+                context: true,
+                ..EncodeOptions::default()
+            },
+            EncodeOptions {
+                coder: crate::header::Coder::Ans,
+                ..EncodeOptions::default()
+            },
+        ];
+        for options in option_sets {
+            let options = EncodeOptions {
+                block_size: 7000,
+                ..options
+            };
+            let encoded = encode(input, &options);
+            for range in [0..0, 0..10, 6990..7010, 150_000..200_000, 12_345..98_765] {
+                assert_eq!(read_range(&encoded, range.clone()), input[range]);
+            }
+            // past the end
+            assert_eq!(read_range(&encoded, 199_990..300_000), input[199_990..]);
+            assert!(read_range(&encoded, 300_000..300_001).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_reads_backwards() {
+        let input: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        let options = EncodeOptions {
+            block_size: 1000,
+            ..EncodeOptions::default()
+        };
+        let encoded = encode(&input, &options);
+        let mut decoder = SeekableDecoder::new(Cursor::new(encoded)).unwrap();
+        assert_eq!(decoder.original_len(), 50_000);
+
+        let mut byte = [0u8; 1];
+        for position in (0..50_000).rev().step_by(997) {
+            decoder.seek(SeekFrom::Start(position)).unwrap();
+            decoder.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], input[position as usize]);
+        }
+        decoder.seek(SeekFrom::End(-1)).unwrap();
+        decoder.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], input[49_999]);
+        assert!(decoder.seek(SeekFrom::Current(-100_000)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_indexes() {
+        let input = b"the index must match the blocks ".repeat(100);
+        let options = EncodeOptions {
+            block_size: 500,
+            ..EncodeOptions::default()
+        };
+        let encoded = encode(&input, &options);
+
+        // the start of the second entry points far past its block
+        let index_offset = {
+            let footer = encoded.len() - INDEX_FOOTER_LEN as usize;
+            u64::from_le_bytes(encoded[footer..footer + 8].try_into().unwrap()) as usize
+        };
+        let mut bad_start = encoded.clone();
+        bad_start[index_offset + 24 + 16 + 4] ^= 0x01;
+        let err = SeekableDecoder::new(Cursor::new(bad_start)).err().unwrap();
+        assert_eq!(err.to_string(), "archive is corrupt: bad seek index");
+
+        // a wrong original offset is only found when reading the block
+        let mut bad_offset = encoded.clone();
+        bad_offset[index_offset + 24] ^= 0x01;
+        let mut decoder = SeekableDecoder::new(Cursor::new(bad_offset)).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        assert!(decoder.read_to_end(&mut decoded).is_err());
+
+        // the streaming decoder checks the index too
+        let mut bad_index = encoded.clone();
+        bad_index[index_offset + 8] ^= 0x01;
+        let err = crate::decode::decode_stream(bad_index.as_slice(), io::sink()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "archive is corrupt: seek index does not match the blocks"
+        );
+    }
+}